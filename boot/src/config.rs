@@ -0,0 +1,197 @@
+//! the config module reads the bootloader configuration file. the file is
+//! optional, and lives at `/efi/demos/boot.cfg` on the boot volume. it's a
+//! list of `key=value` lines, and everything after a `#` on a line is a
+//! comment. the keys it understands are
+//!
+//! * `entry` - starts a new boot entry with the given name
+//! * `kernel` - the path of the kernel on the boot volume
+//! * `module` - the path of a module to load alongside the kernel. it can be
+//!   given more than once.
+//! * `cmdline` - the command line to hand to the kernel
+//! * `timeout` - how many seconds the boot menu waits before booting the
+//!   first entry
+//! * `log_level` - the maximum level the bootloader logs at
+//! * `video_mode` - the preferred video mode, as `<width>x<height>`
+//!
+//! `kernel`, `module`, and `cmdline` apply to the entry they come after. if
+//! they come before any entry, they are the defaults every entry starts out
+//! with. if there aren't any entries at all, the defaults are the only entry.
+
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::str;
+use efi;
+use error::BootError;
+use log::LevelFilter;
+use uefi::proto::media;
+
+/// CONFIG_PATH is where we look for the configuration file on the boot
+/// volume.
+pub const CONFIG_PATH: &str = "/efi/demos/boot.cfg";
+
+/// DEFAULT_TIMEOUT is how many seconds the boot menu waits if the config
+/// doesn't say.
+const DEFAULT_TIMEOUT: usize = 3;
+
+#[derive(Debug)]
+pub struct Config {
+    /// entries are the kernels we can boot. there is always at least one.
+    pub entries: Vec<Entry>,
+    /// timeout is how many seconds the boot menu waits before booting the
+    /// first entry.
+    pub timeout: usize,
+    /// log_level is the maximum level the bootloader logs at.
+    pub log_level: LevelFilter,
+    /// video_mode is the preferred resolution, if there is one.
+    pub video_mode: Option<(usize, usize)>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            entries: vec![Entry::default()],
+            timeout: DEFAULT_TIMEOUT,
+            log_level: LevelFilter::Trace,
+            video_mode: None,
+        }
+    }
+}
+
+/// Entry is a single kernel we can boot, along with everything that goes with
+/// it.
+#[derive(Debug, Clone)]
+pub struct Entry {
+    /// name is what the boot menu calls the entry.
+    pub name: String,
+    /// kernel is the path of the kernel on the boot volume.
+    pub kernel: String,
+    /// modules are the paths of the modules to load alongside the kernel.
+    pub modules: Vec<String>,
+    /// cmdline is handed to the kernel as-is.
+    pub cmdline: String,
+}
+
+impl Default for Entry {
+    fn default() -> Self {
+        Entry {
+            name: "DemOS".to_string(),
+            kernel: "/kernel".to_string(),
+            modules: Vec::new(),
+            cmdline: String::new(),
+        }
+    }
+}
+
+impl Config {
+    /// load reads the configuration file from the given directory, which
+    /// should be the root of the boot volume. if there isn't one, or we can't
+    /// read it, we just use the defaults. a broken config shouldn't stop us
+    /// from booting.
+    pub fn load(root: &mut media::File) -> Self {
+        let (buf, addr) = match efi::read_file(root, CONFIG_PATH) {
+            Ok(file) => file,
+            Err(BootError::FileNotFound(_)) => {
+                debug!("no config file found at {}, using defaults", CONFIG_PATH);
+                return Config::default();
+            },
+            Err(e) => {
+                warn!("{}, using defaults", e);
+                return Config::default();
+            },
+        };
+
+        let config = match str::from_utf8(buf) {
+            Ok(text) => Config::parse(text),
+            Err(e) => {
+                warn!("config file {} isn't valid utf-8: {}", CONFIG_PATH, e);
+                Config::default()
+            },
+        };
+
+        // we don't need the file anymore. if giving it back fails, we just
+        // leak a page, which isn't worth failing to boot over.
+        let size = buf.len();
+        if let Err(status) = efi::free(addr, size) {
+            warn!("failed to free the config file: {:?}", status);
+        }
+
+        config
+    }
+
+    /// parse parses the text of a configuration file. anything it doesn't
+    /// understand gets a warning and is otherwise ignored.
+    pub fn parse(text: &str) -> Self {
+        let mut config = Config::default();
+        // the defaults are what every entry starts out as. until the first
+        // entry line, that's what we are filling in.
+        let mut defaults = Entry::default();
+        let mut entries: Vec<Entry> = Vec::new();
+
+        for (n, line) in text.lines().enumerate() {
+            let line = match line.find('#') {
+                Some(i) => &line[..i],
+                None => line,
+            }.trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let mut parts = line.splitn(2, '=');
+            let key = parts.next().unwrap().trim();
+            let value = match parts.next() {
+                Some(value) => value.trim(),
+                None => {
+                    warn!("{}:{}: expected key=value", CONFIG_PATH, n + 1);
+                    continue;
+                },
+            };
+
+            // a new entry starts out as a copy of the defaults
+            if key == "entry" {
+                let mut entry = defaults.clone();
+                entry.name = value.to_string();
+                entries.push(entry);
+                continue;
+            }
+
+            let current = entries.last_mut().unwrap_or(&mut defaults);
+            match key {
+                "kernel" => current.kernel = value.to_string(),
+                "module" => current.modules.push(value.to_string()),
+                "cmdline" => current.cmdline = value.to_string(),
+                "timeout" => match value.parse() {
+                    Ok(timeout) => config.timeout = timeout,
+                    Err(_) => warn!("{}:{}: timeout should be a number of seconds, not {}",
+                                    CONFIG_PATH, n + 1, value),
+                },
+                "log_level" => match value.parse() {
+                    Ok(level) => config.log_level = level,
+                    Err(_) => warn!("{}:{}: unknown log level {}",
+                                    CONFIG_PATH, n + 1, value),
+                },
+                "video_mode" => match parse_video_mode(value) {
+                    Some(mode) => config.video_mode = Some(mode),
+                    None => warn!("{}:{}: video mode should look like 1024x768, not {}",
+                                  CONFIG_PATH, n + 1, value),
+                },
+                _ => warn!("{}:{}: unknown key {}", CONFIG_PATH, n + 1, key),
+            }
+        }
+
+        if !entries.is_empty() {
+            config.entries = entries;
+        } else {
+            config.entries = vec![defaults];
+        }
+
+        config
+    }
+}
+
+/// parse_video_mode parses a video mode that looks like `1024x768`.
+fn parse_video_mode(value: &str) -> Option<(usize, usize)> {
+    let mut parts = value.splitn(2, 'x');
+    let width = parts.next()?.trim().parse().ok()?;
+    let height = parts.next()?.trim().parse().ok()?;
+    Some((width, height))
+}
@@ -1,11 +1,20 @@
 //! the efi module handles all the uefi interactions
 
+use alloc::string::String;
 use core::{mem, slice};
-use uefi::{self, table, table::boot};
+use error::{BootError, Context, Result};
+use pool;
+use proto::LoadedImage;
+use uefi::{self, table, table::boot, table::cfg};
+use uefi::proto::media;
 use uefi_services;
 
-pub fn alloc_addr<'a>(bytes: usize) -> Result<(&'a mut [u8], usize), uefi::Status> {
-    let size = bytes / 4096;
+pub fn alloc_addr<'a>(bytes: usize)
+                     -> ::core::result::Result<(&'a mut [u8], usize), uefi::Status>
+{
+    // round up, so we never hand back a slice that runs off the end of the
+    // pages we actually got.
+    let size = (bytes + 4095) / 4096;
     trace!("allocating {} pages for {} bytes", size, bytes);
     let pages = uefi_services::system_table().boot.allocate_pages(
         boot::AllocateType::AnyPages,
@@ -18,10 +27,85 @@ pub fn alloc_addr<'a>(bytes: usize) -> Result<(&'a mut [u8], usize), uefi::Statu
     }
 }
 
-pub fn alloc<'a>(bytes: usize) -> Result<&'a mut [u8], uefi::Status> {
+pub fn alloc<'a>(bytes: usize) -> ::core::result::Result<&'a mut [u8], uefi::Status> {
     alloc_addr(bytes).map(|(b, _)| b)
 }
 
+/// read_file reads the whole file at the given path into freshly allocated
+/// LoaderData pages, and returns them along with their physical address. the
+/// path is relative to dir, and uses forward slashes like a normal person,
+/// which get turned into the backslashes uefi wants.
+pub fn read_file<'a>(dir: &mut media::File, path: &str) -> Result<(&'a mut [u8], usize)> {
+    let file_error = |status| match status {
+        uefi::Status::NotFound => BootError::FileNotFound(path.into()),
+        status => BootError::File(path.into(), status),
+    };
+
+    let uefi_path: String = path.trim_left_matches('/')
+        .chars()
+        .map(|c| if c == '/' { '\\' } else { c })
+        .collect();
+
+    // the uefi file protocol allows you to open files on a filesystem using the
+    // name, relative to the location of a file you already have open. using
+    // open_volume with the simple file system protocol provides us with a file
+    // that represents the root directory of the filesystem.
+    let mut file = dir.open(&uefi_path,
+                            media::FileMode::READ,
+                            media::FileAttribute::NONE)
+        .map_err(file_error)?;
+
+    // find the size of the file by setting the position to the end of the file
+    // and getting the position of both sides. then set it back to the beginning
+    // of the file so we can read it. the start_pos should always be zero but
+    // I'm not confident enough in that assumption to rely on it, so we might as
+    // well just do the simple math.
+    let start_pos = file.get_position().map_err(file_error)?;
+    file.set_position(0xFFFFFFFFFFFFFFFF).map_err(file_error)?;
+    let end_pos = file.get_position().map_err(file_error)?;
+    file.set_position(0).map_err(file_error)?;
+    let size = (end_pos - start_pos) as usize;
+
+    // use the size to allocate a buffer in memory to read the file into
+    let (buf, addr) = alloc_addr(size).context("allocate memory for a file")?;
+
+    // read the file into memory
+    let bytes_read = file.read(buf).map_err(file_error)?;
+    // sanity check: make sure everything has the right number of bytes
+    if size != bytes_read {
+        return Err(BootError::ShortRead {
+            path: path.into(),
+            expected: size,
+            read: bytes_read,
+        });
+    }
+
+    Ok((buf, addr))
+}
+
+/// max_memory_regions returns an upper bound on the number of entries in the
+/// memory map that fit in the given buffer. the firmware's descriptors are at
+/// least as big as ours, so this is never too small.
+pub fn max_memory_regions(map_buffer: &[u8]) -> usize {
+    map_buffer.len() / mem::size_of::<boot::MemoryDescriptor>()
+}
+
+/// free gives pages we got from alloc_addr back to the firmware.
+pub fn free(addr: usize, bytes: usize) -> ::core::result::Result<(), uefi::Status> {
+    let size = (bytes + 4095) / 4096;
+    trace!("freeing {} pages at {:#x}", size, addr);
+    uefi_services::system_table().boot.free_pages(addr, size)
+}
+
+/// MEMORY_MAP_SLACK is how much bigger than the current memory map we make the
+/// buffer we put the final memory map in.
+const MEMORY_MAP_SLACK: usize = 4 * 4096;
+
+/// EXIT_BOOT_SERVICES_RETRIES is how many times we try to exit boot services
+/// before giving up. the firmware shouldn't change the memory map more than
+/// once or twice while we are doing this.
+const EXIT_BOOT_SERVICES_RETRIES: usize = 8;
+
 pub struct Efi {
     handle: uefi::Handle,
     st: &'static table::SystemTable,
@@ -30,7 +114,7 @@ pub struct Efi {
 impl Efi {
     /// init calls necessary initialization functions to set up our uefi
     /// environment.
-    pub fn init(handle: uefi::Handle, st: &'static table::SystemTable) -> Self {
+    pub fn init(handle: uefi::Handle, st: &'static table::SystemTable) -> Result<Self> {
         // initialize uefi_services. this sets up logging and allocation and
         // initializes a globally accessible reference to the system table.
         uefi_services::init(st);
@@ -38,38 +122,145 @@ impl Efi {
         // get the handle to stdout, and reset it
         let stdout = st.stdout();
         stdout.reset(false)
-            .expect("failed to reset stdout");
+            .context("reset stdout")?;
 
-        // Switch to the maximum supported graphics mode. should be safe to
-        // unwrap here because we are getting the last mode, and there should be
-        // /any/ modes available.
+        // Switch to the maximum supported graphics mode. there should be /any/
+        // modes available, but if there aren't, there isn't much point going
+        // on.
         let best_mode = stdout.modes().last()
-            .expect("failed to get the best mode");
+            .ok_or(uefi::Status::Unsupported)
+            .context("get the best stdout mode")?;
         stdout.set_mode(best_mode)
-            .expect("failed to set stdout mode the best mode");
+            .context("set stdout mode to the best mode")?;
 
-        Efi {handle, st}
+        Ok(Efi {handle, st})
     }
 
-    pub fn get_memory_map<'a>(&self) -> (boot::MemoryMapKey, boot::MemoryMapIter<'a>) {
-        trace!("getting the memory map");
+    /// boot_volume opens the root directory of the filesystem we were loaded
+    /// from. the firmware tells us which device that is through the
+    /// LoadedImage protocol on our own image handle, and that device has the
+    /// SimpleFileSystem protocol on it if it's something we can read files
+    /// from. this way we always read the kernel from the same disk as the
+    /// bootloader, even if there are other disks attached.
+    pub fn boot_volume(&self) -> Result<media::File> {
+        let loaded_image = self.st.boot.handle_protocol::<LoadedImage>(self.handle)
+            .ok_or(uefi::Status::Unsupported)
+            .context("get the LoadedImage protocol for the bootloader image")?;
+        let device = unsafe { loaded_image.as_ref() }.device();
+
+        let mut sfs_ptr = self.st.boot.handle_protocol::<media::SimpleFileSystem>(device)
+            .ok_or(uefi::Status::Unsupported)
+            .context("get the SimpleFileSystem protocol for the boot device")?;
+        let sfs = unsafe { sfs_ptr.as_mut() };
+
+        sfs.open_volume().context("open the boot volume")
+    }
+
+    /// config_table_address returns the address of the first entry in the
+    /// configuration table with one of the given guids, trying them in order.
+    fn config_table_address(&self, guids: &[uefi::Guid]) -> Option<u64> {
+        let entries = self.st.config_table();
+        guids.iter()
+            .filter_map(|guid| entries.iter().find(|entry| entry.guid == *guid))
+            .map(|entry| entry.address as u64)
+            .next()
+    }
+
+    /// acpi_rsdp returns the physical address of the ACPI root system
+    /// description pointer. we prefer the ACPI 2.0 one, since it points at the
+    /// 64-bit XSDT, but fall back on the 1.0 one.
+    pub fn acpi_rsdp(&self) -> Option<u64> {
+        self.config_table_address(&[cfg::ACPI2_GUID, cfg::ACPI_GUID])
+    }
+
+    /// smbios returns the physical address of the SMBIOS entry point. we
+    /// prefer the 64-bit SMBIOS 3 one, but fall back on the 32-bit one.
+    pub fn smbios(&self) -> Option<u64> {
+        self.config_table_address(&[cfg::SMBIOS3_GUID, cfg::SMBIOS_GUID])
+    }
+
+    /// alloc_memory_map_buffer allocates a buffer to hold the memory map.
+    /// we only get one chance to allocate it, since once we start trying to
+    /// exit boot services, we aren't allowed to allocate anymore. so, we
+    /// allocate more space than we need, to leave room for the map to grow
+    /// with the allocations that happen between now and then. importantly,
+    /// this is okay because we aren't using the size of the slice to inform
+    /// how many entries are in it. the underlying uefi function returns this
+    /// information explicitly, and the iterator stores it.
+    pub fn alloc_memory_map_buffer(&self) -> Result<&'static mut [u8]> {
         let map_size = self.st.boot.memory_map_size();
-        // just in case allocating these additional pages increases the size of the
-        // memory map, we allocate more space than we need. importantly, this is
-        // okay because we aren't using the size of the slice to inform how many
-        // entries are in it. the underlying uefi function returns this information
-        // explicitly, and the iterator stores it.
-        let map_buffer = alloc(map_size + 4096)
-            .expect("failed to allocate memory for memory map");
+        alloc(map_size + MEMORY_MAP_SLACK)
+            .context("allocate memory for the memory map")
+    }
+
+    /// page_table_frames returns an upper bound on the number of page table
+    /// frames it takes to identity map everything in the current memory map.
+    pub fn page_table_frames(&self, map_buffer: &mut [u8]) -> Result<usize> {
+        let (_, desc) = self.get_memory_map(map_buffer)?;
+        let frames: usize = desc
+            .map(|d| pool::page_table_frames(d.page_count as usize * 4096))
+            .sum();
+        Ok(frames + 16)
+    }
+
+    /// runtime_page_table_frames returns an upper bound on the number of page
+    /// table frames it takes to map the runtime regions in the current memory
+    /// map a second time, at their runtime addresses.
+    pub fn runtime_page_table_frames(&self, map_buffer: &mut [u8]) -> Result<usize> {
+        let (_, desc) = self.get_memory_map(map_buffer)?;
+        let frames: usize = desc
+            .filter(|d| d.att.contains(boot::MemoryAttribute::RUNTIME))
+            .map(|d| pool::page_table_frames(d.page_count as usize * 4096))
+            .sum();
+        Ok(frames)
+    }
+
+    fn get_memory_map<'a>(&self, map_buffer: &'a mut [u8])
+                          -> Result<(boot::MemoryMapKey, boot::MemoryMapIter<'a>)>
+    {
+        trace!("getting the memory map");
         self.st.boot.memory_map(map_buffer)
-            .expect("failed to get memory map")
+            .context("get the memory map")
     }
 
-    pub fn exit_boot_services(self, key: boot::MemoryMapKey) {
-        // retrieving an updated memory map again.
-        unsafe {
-            self.st.boot.exit_boot_services(self.handle, key)
-                .expect("failed to exit boot services");
+    /// exit_boot_services gets the final memory map and exits boot services
+    /// with it. the firmware is allowed to change the memory map right up
+    /// until we exit, in which case it tells us the key we gave it is stale
+    /// with INVALID_PARAMETER. when that happens, we get the memory map again
+    /// and retry. we can't allocate or log in here, since either of those can
+    /// change the memory map themselves, so the buffer has to be big enough
+    /// already. it returns the final memory map. once we have asked the
+    /// firmware to exit boot services, even if it said no, all it guarantees
+    /// still works is getting the memory map and trying again. so only a
+    /// failure to get the memory map the first time is a normal error. any
+    /// failure after that is an ExitBootServices error, which must not be
+    /// reported through the console or anything else in boot services.
+    pub fn exit_boot_services<'a>(self, map_buffer: &'a mut [u8])
+                                  -> Result<boot::MemoryMapIter<'a>>
+    {
+        let (buf_ptr, buf_len) = (map_buffer.as_mut_ptr(), map_buffer.len());
+
+        for attempt in 0..EXIT_BOOT_SERVICES_RETRIES {
+            // the memory map iterator borrows the buffer, and we need to hand
+            // it back on success but drop it and refill the buffer on failure.
+            // the borrow checker can't tell those apart in a loop, so we make
+            // a fresh borrow out of the raw parts every time around.
+            let buf = unsafe { slice::from_raw_parts_mut(buf_ptr, buf_len) };
+            let (key, desc) = match self.st.boot.memory_map(buf) {
+                Ok(map) => map,
+                Err(status) if attempt == 0 =>
+                    return Err(BootError::Uefi("get the final memory map", status)),
+                Err(status) => return Err(BootError::ExitBootServices(status)),
+            };
+
+            match unsafe { self.st.boot.exit_boot_services(self.handle, key) } {
+                Ok(()) => return Ok(desc),
+                Err(uefi::Status::InvalidParameter) => continue,
+                Err(status) => return Err(BootError::ExitBootServices(status)),
+            }
         }
+
+        // the key was still stale on the last try
+        Err(BootError::ExitBootServices(uefi::Status::InvalidParameter))
     }
 }
@@ -0,0 +1,132 @@
+//! the error module defines the error type for the bootloader. panicking in
+//! firmware just leaves a hung screen, so anything that can go wrong before we
+//! exit boot services gets turned into a BootError instead, which makes its way
+//! back up to uefi_start. there, we print it and hand the firmware a status
+//! code, so it can move on to the next boot entry. the one exception is when
+//! exiting boot services itself fails, since by then we can't print anything.
+
+use alloc::string::String;
+use core::fmt;
+use goblin;
+use manifest::DIGEST_SIZE;
+use uefi::Status;
+
+pub type Result<T> = ::core::result::Result<T, BootError>;
+
+#[derive(Debug)]
+pub enum BootError {
+    /// a call into the firmware failed. the string says what we were trying to
+    /// do at the time.
+    Uefi(&'static str, Status),
+    /// exiting boot services failed after we had already asked the firmware
+    /// to do it once. the firmware is allowed to have torn down some of boot
+    /// services by then, so this one can't be printed, just acted on.
+    ExitBootServices(Status),
+    /// a file we need isn't on the boot volume.
+    FileNotFound(String),
+    /// a file we need is on the boot volume, but we couldn't read it.
+    File(String, Status),
+    /// we read fewer bytes from a file than it says it has.
+    ShortRead { path: String, expected: usize, read: usize },
+    /// the kernel isn't an elf file we understand.
+    Elf(goblin::error::Error),
+    /// the kernel doesn't have anything in it to load.
+    NoLoadableSegments,
+    /// the kernel has a segment, at the given virtual address, that runs off
+    /// the end of the file or is bigger in the file than in memory.
+    BadSegment(u64),
+    /// the kernel has a relocation we don't know how to apply. we only handle
+    /// R_X86_64_RELATIVE, since that is all a static pie should have.
+    UnsupportedRelocation(u32),
+    /// the kernel has a relocation that points outside all of its segments.
+    BadRelocation(usize),
+    /// a file doesn't match its digest in the manifest, so it isn't what the
+    /// build put there.
+    DigestMismatch { path: String, expected: [u8; DIGEST_SIZE], actual: [u8; DIGEST_SIZE] },
+    /// there is a manifest, but a file we load isn't in it, so there is
+    /// nothing to say it's what the build put there.
+    NotInManifest(String),
+}
+
+impl BootError {
+    /// status returns the uefi status code that best describes the error, to
+    /// return to the firmware.
+    pub fn status(&self) -> Status {
+        match *self {
+            BootError::Uefi(_, status) => status,
+            BootError::ExitBootServices(status) => status,
+            BootError::FileNotFound(_) => Status::NotFound,
+            BootError::File(_, status) => status,
+            BootError::ShortRead { .. } => Status::EndOfFile,
+            BootError::Elf(_) => Status::LoadError,
+            BootError::NoLoadableSegments => Status::LoadError,
+            BootError::BadSegment(_) => Status::LoadError,
+            BootError::UnsupportedRelocation(_) => Status::LoadError,
+            BootError::BadRelocation(_) => Status::LoadError,
+            BootError::DigestMismatch { .. } => Status::CrcError,
+            BootError::NotInManifest(_) => Status::SecurityViolation,
+        }
+    }
+}
+
+impl fmt::Display for BootError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BootError::Uefi(context, status) =>
+                write!(f, "failed to {}: {:?}", context, status),
+            BootError::ExitBootServices(status) =>
+                write!(f, "failed to exit boot services: {:?}", status),
+            BootError::FileNotFound(ref path) =>
+                write!(f, "{} not found on the boot device", path),
+            BootError::File(ref path, status) =>
+                write!(f, "failed to read {}: {:?}", path, status),
+            BootError::ShortRead { ref path, expected, read } =>
+                write!(f, "short read of {}: read {} of {} bytes", path, read, expected),
+            BootError::Elf(ref e) =>
+                write!(f, "failed to parse kernel elf: {}", e),
+            BootError::NoLoadableSegments =>
+                write!(f, "kernel has no loadable segments"),
+            BootError::BadSegment(vaddr) =>
+                write!(f, "kernel segment at {:#x} doesn't fit in the file or in memory", vaddr),
+            BootError::UnsupportedRelocation(r_type) =>
+                write!(f, "kernel has a relocation of unsupported type {}", r_type),
+            BootError::BadRelocation(offset) =>
+                write!(f, "kernel has a relocation at {:#x}, outside all of its segments", offset),
+            BootError::DigestMismatch { ref path, ref expected, ref actual } =>
+                write!(f, "{} doesn't match the manifest, refusing to boot it: expected sha-256 {}, got {}",
+                       path, Hex(expected), Hex(actual)),
+            BootError::NotInManifest(ref path) =>
+                write!(f, "{} isn't in the manifest, refusing to boot it", path),
+        }
+    }
+}
+
+/// Hex displays a byte slice as lowercase hex, the same way sha256sum does.
+struct Hex<'a>(&'a [u8]);
+
+impl<'a> fmt::Display for Hex<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for byte in self.0 {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl From<goblin::error::Error> for BootError {
+    fn from(e: goblin::error::Error) -> Self {
+        BootError::Elf(e)
+    }
+}
+
+/// Context adds some context to a bare uefi status, turning it into a
+/// BootError.
+pub trait Context<T> {
+    fn context(self, context: &'static str) -> Result<T>;
+}
+
+impl<T> Context<T> for ::core::result::Result<T, Status> {
+    fn context(self, context: &'static str) -> Result<T> {
+        self.map_err(|status| BootError::Uefi(context, status))
+    }
+}
@@ -0,0 +1,200 @@
+//! the info module builds the BootInfo struct that gets handed to the kernel.
+//! everything in it has to be allocated before the final memory map is
+//! retrieved, since allocating changes the memory map, and after we exit boot
+//! services we can't allocate anything anyway.
+
+use bootinfo::{BootInfo, BootTimes, CmdLine, FrameBuffer, KernelExtents, KernelSegment, MemoryMap,
+               MemoryRegion, MemoryRegionKind, Module, ModuleList, SegmentList};
+use core::{mem, ptr, slice};
+use efi;
+use uefi::{self, table::boot};
+
+pub struct Handoff {
+    info: &'static mut BootInfo,
+    regions: &'static mut [MemoryRegion],
+}
+
+impl Handoff {
+    /// alloc allocates the BootInfo struct and an array with room for
+    /// max_regions memory regions in LoaderData pages, so they survive the
+    /// trip into the kernel.
+    pub fn alloc(max_regions: usize) -> Result<Self, uefi::Status> {
+        let info_buf = efi::alloc(mem::size_of::<BootInfo>())?;
+        let region_buf = efi::alloc(max_regions * mem::size_of::<MemoryRegion>())?;
+
+        let info = unsafe {
+            let info_ptr = info_buf.as_mut_ptr() as *mut BootInfo;
+            ptr::write(info_ptr, BootInfo::new());
+            &mut *info_ptr
+        };
+        let regions = unsafe {
+            slice::from_raw_parts_mut(region_buf.as_mut_ptr() as *mut MemoryRegion,
+                                      max_regions)
+        };
+
+        Ok(Handoff { info, regions })
+    }
+
+    /// set_kernel records where the kernel is in memory.
+    pub fn set_kernel(&mut self, extents: KernelExtents) {
+        self.info.kernel = extents;
+    }
+
+    /// set_kernel_segments copies the descriptions of the kernel segments
+    /// somewhere they will survive the trip into the kernel.
+    pub fn set_kernel_segments(&mut self, segments: &[KernelSegment]) -> Result<(), uefi::Status> {
+        let buf = efi::alloc(segments.len() * mem::size_of::<KernelSegment>())?;
+        let list = unsafe {
+            slice::from_raw_parts_mut(buf.as_mut_ptr() as *mut KernelSegment, segments.len())
+        };
+        list.copy_from_slice(segments);
+        self.info.kernel_segments = unsafe {
+            SegmentList::from_raw_parts(list.as_ptr(), list.len())
+        };
+        Ok(())
+    }
+
+    /// set_cmdline copies the kernel command line somewhere it will survive
+    /// the trip into the kernel.
+    pub fn set_cmdline(&mut self, cmdline: &str) -> Result<(), uefi::Status> {
+        if cmdline.is_empty() {
+            return Ok(());
+        }
+
+        let buf = efi::alloc(cmdline.len())?;
+        buf.copy_from_slice(cmdline.as_bytes());
+        self.info.cmdline = unsafe {
+            CmdLine::from_raw_parts(buf.as_ptr(), buf.len())
+        };
+        Ok(())
+    }
+
+    /// set_framebuffer copies the framebuffer description somewhere it will
+    /// survive the trip into the kernel.
+    pub fn set_framebuffer(&mut self, framebuffer: FrameBuffer) -> Result<(), uefi::Status> {
+        let buf = efi::alloc(mem::size_of::<FrameBuffer>())?;
+        let fb_ptr = buf.as_mut_ptr() as *mut FrameBuffer;
+        unsafe { ptr::write(fb_ptr, framebuffer) };
+        self.info.framebuffer = fb_ptr;
+        Ok(())
+    }
+
+    /// set_acpi_rsdp records the physical address of the ACPI RSDP.
+    pub fn set_acpi_rsdp(&mut self, addr: u64) {
+        self.info.acpi_rsdp = addr;
+    }
+
+    /// set_smbios records the physical address of the SMBIOS entry point.
+    pub fn set_smbios(&mut self, addr: u64) {
+        self.info.smbios = addr;
+    }
+
+    /// set_runtime_services records the virtual address of the uefi runtime
+    /// services table.
+    pub fn set_runtime_services(&mut self, addr: u64) {
+        self.info.runtime_services = addr;
+    }
+
+    /// set_modules copies the descriptions of the loaded modules somewhere
+    /// they will survive the trip into the kernel.
+    pub fn set_modules(&mut self, modules: &[Module]) -> Result<(), uefi::Status> {
+        if modules.is_empty() {
+            return Ok(());
+        }
+
+        let buf = efi::alloc(modules.len() * mem::size_of::<Module>())?;
+        let list = unsafe {
+            slice::from_raw_parts_mut(buf.as_mut_ptr() as *mut Module, modules.len())
+        };
+        list.copy_from_slice(modules);
+        self.info.modules = unsafe {
+            ModuleList::from_raw_parts(list.as_ptr(), list.len())
+        };
+        Ok(())
+    }
+
+    /// set_memory_map converts the uefi memory map into our own region type.
+    /// the firmware doesn't promise the map is in any particular order, and
+    /// since we collapse a bunch of its memory types into one of ours, there
+    /// are usually a lot of neighboring regions of the same kind. so, we sort
+    /// the regions by address and merge the neighbors, which makes the list a
+    /// lot easier for the kernel to deal with.
+    ///
+    /// this is called after exit_boot_services, so it can't allocate or log
+    /// anything, which is why everything happens in place in the array we
+    /// allocated ahead of time. if there are more descriptors than we made
+    /// room for, the extra ones are dropped, which at worst means the kernel
+    /// doesn't know about some memory.
+    pub fn set_memory_map<'a, I>(&mut self, descriptors: I)
+        where I: Iterator<Item = &'a boot::MemoryDescriptor>
+    {
+        let mut len = 0;
+        for (region, desc) in self.regions.iter_mut().zip(descriptors) {
+            *region = MemoryRegion {
+                start: desc.phys_start,
+                end: desc.phys_start + desc.page_count * 4096,
+                kind: region_kind(desc.ty),
+            };
+            len += 1;
+        }
+
+        let regions = &mut self.regions[..len];
+        regions.sort_unstable_by_key(|region| region.start);
+        let len = coalesce(regions);
+
+        self.info.memory_map = unsafe {
+            MemoryMap::from_raw_parts(self.regions.as_ptr(), len)
+        };
+    }
+
+    /// set_times records when each phase of the boot finished, so far.
+    pub fn set_times(&mut self, times: BootTimes) {
+        self.info.times = times;
+    }
+
+    /// finish hands back the completed BootInfo struct. it's still mutable,
+    /// so the last few boot times can go in after the kernel is remapped.
+    pub fn finish(self) -> &'static mut BootInfo {
+        self.info
+    }
+}
+
+/// coalesce merges neighboring regions of the same kind in a sorted list of
+/// regions. the merged regions are moved to the front of the list, and it
+/// returns how many of them there are.
+fn coalesce(regions: &mut [MemoryRegion]) -> usize {
+    if regions.is_empty() {
+        return 0;
+    }
+
+    let mut last = 0;
+    for i in 1..regions.len() {
+        let region = regions[i];
+        if region.kind == regions[last].kind && region.start == regions[last].end {
+            regions[last].end = region.end;
+        } else {
+            last += 1;
+            regions[last] = region;
+        }
+    }
+
+    last + 1
+}
+
+/// region_kind maps the uefi memory types onto our simplified set of region
+/// kinds.
+fn region_kind(ty: boot::MemoryType) -> MemoryRegionKind {
+    use uefi::table::boot::MemoryType::*;
+
+    match ty {
+        Conventional => MemoryRegionKind::Usable,
+        LoaderCode | LoaderData => MemoryRegionKind::Bootloader,
+        BootServicesCode | BootServicesData => MemoryRegionKind::BootServices,
+        RuntimeServicesCode | RuntimeServicesData => MemoryRegionKind::RuntimeServices,
+        AcpiReclaim => MemoryRegionKind::AcpiReclaimable,
+        AcpiNonVolatile => MemoryRegionKind::AcpiNvs,
+        MemoryMappedIO | MemoryMappedIOPortSpace => MemoryRegionKind::Mmio,
+        Unusable => MemoryRegionKind::Unusable,
+        _ => MemoryRegionKind::Reserved,
+    }
+}
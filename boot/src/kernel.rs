@@ -1,101 +1,291 @@
 //! the kernel module deals with loading, remapping, and entering the kernel,
 //! and keeping track of all the important kernel-related details.
 
-use core::mem;
+use alloc::vec::Vec;
+use bootinfo::{self, BootInfo, KernelExtents, KernelSegment};
+use core::{cmp, mem, ptr};
 use efi;
-use goblin::elf;
-use uefi::{proto::media};
-use uefi_utils::proto::find_protocol;
+use manifest::Manifest;
+use pool;
+use random;
+use goblin::elf::{self, header, program_header, reloc};
+use memory::{Frame, FrameAllocator, PAGE_SIZE};
+use memory::map::{KERNEL_OFFSET, KERNEL_STACK_OFFSET, KERNEL_STACK_SIZE, PML4_SIZE,
+                  RECURSIVE_PAGE_PML4_INDEX, UEFI_RUNTIME_OFFSET};
+use memory::paging::{EntryFlags, Mapper, Page};
+use error::{BootError, Context, Result};
+use uefi::proto::media;
+use uefi::table::boot::MemoryDescriptor;
+use x86_64::instructions::tlb;
+use x86_64::registers::control_regs::{self, Cr0};
 
-type EntryFunc = extern "C" fn() -> !;
+/// SLIDE_ALIGN is what the kernel's randomized base is aligned to. 2MiB keeps
+/// the kernel mappable with large pages.
+const SLIDE_ALIGN: usize = 2 * 1024 * 1024;
 
 pub struct Kernel {
     entry: u64,
-    addr: usize,
+    phys_start: usize,
+    phys_end: usize,
+    virt_start: usize,
+    virt_end: usize,
+    slide: usize,
+    segments: Vec<Segment>,
+    /// relro is the page aligned part of the kernel that only has to be
+    /// writable while we relocate it, if it has one.
+    relro: Option<(usize, usize)>,
+    /// stack_phys is the physical address of the stack the kernel starts out
+    /// on. it's KERNEL_STACK_SIZE bytes of LoaderData pages.
+    stack_phys: usize,
+}
+
+/// Segment is a loadable segment of the kernel elf. it records where the
+/// segment lives in physical memory, where the kernel expects to find it in
+/// virtual memory, and what it is allowed to do with it.
+#[derive(Debug)]
+struct Segment {
+    phys_start: usize,
+    virt_start: usize,
+    size: usize,
+    flags: EntryFlags,
+}
+
+impl Segment {
+    /// load copies a PT_LOAD segment out of the kernel file image into the
+    /// zeroed block the whole kernel is loaded into, which starts at block_phys
+    /// in physical memory and at the link-time address block_virt. the segment
+    /// goes as far into the block as it is past block_virt, so the block can be
+    /// mapped directly. the part of the segment that isn't in the file
+    /// (p_memsz past p_filesz, which is where .bss lives) stays zeroed. a
+    /// segment that runs off the end of the file, or has more in the file than
+    /// in memory, is a BadSegment error.
+    fn load(ph: &program_header::ProgramHeader, image: &[u8], block: &mut [u8], block_phys: usize,
+            block_virt: usize) -> Result<Segment> {
+        let mem_size = ph.p_memsz as usize;
+        let file_size = ph.p_filesz as usize;
+        let file_start = ph.p_offset as usize;
+
+        let file_end = file_start.checked_add(file_size)
+            .filter(|&end| end <= image.len() && file_size <= mem_size)
+            .ok_or(BootError::BadSegment(ph.p_vaddr))?;
+
+        // the block was sized to cover every segment, so this stays inside it.
+        let offset = ph.p_vaddr as usize - block_virt;
+        block[offset..offset + file_size].copy_from_slice(&image[file_start..file_end]);
+
+        trace!("loaded segment {:#x} ({:#x} bytes) at {:#x}",
+               ph.p_vaddr, mem_size, block_phys + offset);
+
+        Ok(Segment {
+            phys_start: block_phys + offset,
+            virt_start: ph.p_vaddr as usize,
+            size: mem_size,
+            flags: segment_flags(ph.p_flags),
+        })
+    }
+
+    /// phys_addr returns the physical address of the given link-time virtual
+    /// address, if it is in this segment and there are at least size bytes
+    /// left after it.
+    fn phys_addr(&self, virt: usize, size: usize) -> Option<usize> {
+        if virt >= self.virt_start && virt + size <= self.virt_start + self.size {
+            Some(self.phys_start + (virt - self.virt_start))
+        } else {
+            None
+        }
+    }
+}
+
+/// segment_flags converts elf program header flags into the equivalent page
+/// table entry flags. the elf flags say what the segment is allowed to do,
+/// while the entry flags mostly say what it is _not_ allowed to do, so the
+/// executable bit is flipped around. everything is readable on x86_64 anyway.
+fn segment_flags(p_flags: u32) -> EntryFlags {
+    let mut flags = EntryFlags::empty();
+
+    if p_flags & program_header::PF_W != 0 {
+        flags = flags | EntryFlags::WRITABLE;
+    }
+    if p_flags & program_header::PF_X == 0 {
+        flags = flags | EntryFlags::NO_EXECUTE;
+    }
+
+    flags
 }
 
 impl Kernel {
-    pub fn load() -> Self {
-        // get the SimpleFileSystem protocol. this is a little weird. afaict, all
-        // uefi function calls basically have an implicit parameter - the handle
-        // used to call the protocol. protocols are only valid on certain handles.
-        // the ones that the simplefilesystem protocol are valid on are device path
-        // handles, and that's how it decides which device to act on. the
-        // find_protocol function gets all handles for which the simplefilesystem
-        // protocol is valid and returns the first one (if there is one).
-        //
-        // a better approach for this particular use case will probably be to
-        // somehow pinpoint the exact device we expect the kernel to be on. one way
-        // to do this would be to use the LoadedImageProtocol to get the device that
-        // our bootloader is loaded from, using the handle to ourselves that we are
-        // passed as a parameter. then, we can explicitly get the SimpleFileSystem
-        // protocol for that handle. this approach is complicated by the fact that
-        // our underlying uefi library doesn't currently implement the
-        // LoadedImageProtocol, so I'm hoping this approach will work instead, at
-        // least for now.
-        let mut sfs_ptr = find_protocol::<media::SimpleFileSystem>()
-            .expect("failed to get SimpleFileSystem protocol: no protocol returned");
-
-        // turn the pointer into a reference.
-        let sfs = unsafe { sfs_ptr.as_mut() };
-
-        // open the root directory of the device
-        let mut root = sfs.open_volume().expect("failed to open volume");
-
-        // the uefi file protocol allows you to open files on a filesystem using the
-        // name, relative to the location of a file you already have open. using
-        // open_volume with the simple file system protocol provides us with a file
-        // that represents the root directory of the filesystem. use that to open
-        // our kernel, which our buildsystem places at `/kernel`.
-        let mut kernel_file = root.open("kernel",
-                                        media::FileMode::READ,
-                                        media::FileAttribute::NONE)
-            .expect("failed to open kernel");
-
-        // find the size of the file by setting the position to the end of the file
-        // and getting the position of both sides. then set it back to the beginning
-        // of the file so we can read it. the start_pos should always be zero but
-        // I'm not confident enough in that assumption to rely on it, so we might as
-        // well just do the simple math.
-        let start_pos = kernel_file.get_position()
-            .expect("failed to get start position in kernel file");
-        kernel_file.set_position(0xFFFFFFFFFFFFFFFF)
-            .expect("failed to set position to end of kernel file");
-        let end_pos = kernel_file.get_position()
-            .expect("failed to get end position in kernel file");
-        kernel_file.set_position(0)
-            .expect("failed to set position to start of kernel file");
-        let kernel_size = (end_pos - start_pos) as usize;
-
-        // use the size to allocate a buffer in memory to read the kernel into. we
-        // don't /really/ care where the kernel ends up at this point, since it's
-        // going to remap itself when it sets up the virtual memory map anyway. we
-        // just need to keep track of the address so we can pass it to the kernel so
-        // it can actually do that.
-        let (kernel, kernel_addr) = efi::alloc_addr(kernel_size)
-            .expect("failed to allocate memory for the kernel");
-
-        // read the kernel from the file into memory
-        let bytes_read = kernel_file.read(kernel)
-            .expect("failed to read kernel into memory");
-        // sanity check: make sure everything has the right number of bytes
-        if kernel_size != bytes_read {
-            panic!("bytes read: {}\nkernel size: {}", bytes_read, kernel_size);
+    /// load loads the kernel at the given path, relative to the given
+    /// directory, which should be the root of the boot volume. if there is a
+    /// manifest, the file has to be in it and match its digest before we load
+    /// anything out of it.
+    pub fn load(root: &mut media::File, path: &str, manifest: Option<&Manifest>) -> Result<Self> {
+        // read the whole kernel file into memory. this is just the file image,
+        // not the kernel as it is going to be laid out in memory, so once we've
+        // copied the segments out of it we give it back to the firmware.
+        let (image, image_addr) = efi::read_file(root, path)?;
+        let kernel_size = image.len();
+
+        if let Some(manifest) = manifest {
+            manifest.verify(path, image)?;
         }
 
-        // okay next we use goblin to parse the elf headers of our kernel
-        let kernel_elf = elf::Elf::parse(kernel)
-            .expect("failed to parse kernel elf");
+        let kernel = {
+            // okay next we use goblin to parse the elf headers of our kernel
+            let kernel_elf = elf::Elf::parse(image)?;
+
+            // the kernel goes into one block of frames, with the loadable
+            // segments as far apart as they are in virtual memory, so it is
+            // physically contiguous and can be mapped in one piece.
+            let loads = || kernel_elf.program_headers.iter()
+                .filter(|ph| ph.p_type == program_header::PT_LOAD);
+            let block_virt = loads()
+                .map(|ph| ph.p_vaddr as usize)
+                .min()
+                .ok_or(BootError::NoLoadableSegments)? / PAGE_SIZE * PAGE_SIZE;
+            let mut block_end = block_virt;
+            for ph in loads() {
+                let end = (ph.p_vaddr as usize).checked_add(ph.p_memsz as usize)
+                    .and_then(|end| end.checked_add(PAGE_SIZE - 1))
+                    .ok_or(BootError::BadSegment(ph.p_vaddr))?;
+                block_end = cmp::max(block_end, end / PAGE_SIZE * PAGE_SIZE);
+            }
+            let (block, block_phys) = efi::alloc_addr(block_end - block_virt)
+                .context("allocate memory for the kernel")?;
+            for byte in block.iter_mut() {
+                *byte = 0;
+            }
 
-        let entry_ptr = kernel_elf.header.e_entry;
+            // this vector is allocated now, while we still have boot services,
+            // and never freed, since we never return from the kernel.
+            let mut segments = loads()
+                .map(|ph| Segment::load(ph, image, block, block_phys, block_virt))
+                .collect::<Result<Vec<Segment>>>()?;
+
+            // a position independent kernel can go anywhere in its pml4 slot,
+            // so pick somewhere random. anything else has to go where it was
+            // linked.
+            let slide = if kernel_elf.header.e_type == header::ET_DYN {
+                choose_slide(&segments)
+            } else {
+                warn!("kernel isn't position independent, not randomizing its base");
+                0
+            };
+            relocate(&kernel_elf, &mut segments, slide)?;
+
+            // the PT_GNU_RELRO segment covers things like .dynamic and the
+            // vtables, which only hold relocated pointers. once they are
+            // fixed up they can be read-only, at least the whole pages of it.
+            let relro = kernel_elf.program_headers.iter()
+                .find(|ph| ph.p_type == program_header::PT_GNU_RELRO)
+                .and_then(|ph| {
+                    let vaddr = ph.p_vaddr as usize + slide;
+                    let start = (vaddr + PAGE_SIZE - 1) / PAGE_SIZE * PAGE_SIZE;
+                    let end = (vaddr + ph.p_memsz as usize) / PAGE_SIZE * PAGE_SIZE;
+                    if start < end { Some((start, end)) } else { None }
+                });
+
+            // the stack we are running on belongs to the firmware, and lives
+            // in boot services memory the kernel is going to reclaim, so the
+            // kernel gets a stack of its own.
+            let (_, stack_phys) = efi::alloc_addr(KERNEL_STACK_SIZE)
+                .context("allocate the kernel stack")?;
+
+            Kernel::new(kernel_elf.header.e_entry + slide as u64, block_phys, block.len(),
+                        segments, relro, slide, stack_phys)
+        };
+
+        efi::free(image_addr, kernel_size)
+            .context("free the kernel image")?;
+
+        Ok(kernel)
+    }
+
+    /// new makes a Kernel out of its entry point, the block of size bytes at
+    /// phys_start it was loaded into, and the segments in that block. the
+    /// extents of the kernel are exactly that block, which starts at the page
+    /// the lowest segment starts in.
+    fn new(entry: u64, phys_start: usize, size: usize, segments: Vec<Segment>,
+           relro: Option<(usize, usize)>, slide: usize, stack_phys: usize) -> Self {
+        let virt_start = segments.iter()
+            .map(|s| s.virt_start / PAGE_SIZE * PAGE_SIZE)
+            .min().unwrap();
 
         Kernel {
-            entry: entry_ptr,
-            addr: kernel_addr,
+            entry,
+            phys_start,
+            phys_end: phys_start + size,
+            virt_start,
+            virt_end: virt_start + size,
+            slide,
+            segments,
+            relro,
+            stack_phys,
         }
     }
 
-    pub fn remap(&self) {
+    /// page_table_frames returns an upper bound on the number of page table
+    /// frames it takes to map the kernel into the higher half.
+    pub fn page_table_frames(&self) -> usize {
+        pool::page_table_frames(self.virt_end - self.virt_start) + 2 * self.segments.len() +
+            pool::page_table_frames(KERNEL_STACK_SIZE)
+    }
+
+    /// extents describes where the kernel is in memory, for the boot info.
+    pub fn extents(&self) -> KernelExtents {
+        KernelExtents {
+            phys_start: self.phys_start as u64,
+            phys_end: self.phys_end as u64,
+            virt_start: self.virt_start as u64,
+            virt_end: self.virt_end as u64,
+            slide: self.slide as u64,
+        }
+    }
+
+    /// segments describes each kernel segment, for the boot info. a segment
+    /// with the relro region in it is split around it, so the kernel keeps
+    /// that part read-only when it makes its own page tables.
+    pub fn segments(&self) -> Vec<KernelSegment> {
+        let mut segments = Vec::with_capacity(self.segments.len() + 2);
+        for segment in &self.segments {
+            let mut flags = 0;
+            if segment.flags.contains(EntryFlags::WRITABLE) {
+                flags |= bootinfo::SEGMENT_WRITABLE;
+            }
+            if !segment.flags.contains(EntryFlags::NO_EXECUTE) {
+                flags |= bootinfo::SEGMENT_EXECUTABLE;
+            }
+
+            let segment_end = segment.virt_start + segment.size;
+            let (relro_start, relro_end) = match self.relro {
+                Some((start, end)) if start >= segment.virt_start && end <= segment_end => {
+                    (start, end)
+                }
+                _ => (segment_end, segment_end),
+            };
+            let pieces = [
+                (segment.virt_start, relro_start, flags),
+                (relro_start, relro_end, flags & !bootinfo::SEGMENT_WRITABLE),
+                (relro_end, segment_end, flags),
+            ];
+            for &(start, end, flags) in pieces.iter().filter(|&&(start, end, _)| start < end) {
+                segments.push(KernelSegment {
+                    phys_start: (segment.phys_start + (start - segment.virt_start)) as u64,
+                    virt_start: start as u64,
+                    size: (end - start) as u64,
+                    flags,
+                });
+            }
+        }
+        segments
+    }
+
+    /// remap builds the page tables the kernel starts out with and switches to
+    /// them. it has to be called after exiting boot services, since it relies
+    /// on the memory map not changing anymore. the runtime regions get mapped
+    /// a second time, at the virtual addresses the firmware was told about.
+    pub fn remap<A>(&self, boot_info: &BootInfo, runtime: &[MemoryDescriptor], allocator: &mut A)
+        where A: FrameAllocator
+    {
         // uefi dumps us into long mode, so paging is already enabled. it identity
         // maps everything we care about, so the table we have right now isn't
         // particularly useful, but it's a start.
@@ -109,7 +299,10 @@ impl Kernel {
         // importantly, this known address is _not_ a valid physical address. it's
         // much to large for that. instead, we tell the linker that our kernel
         // expects to be loaded into a really high page in memory. we use the second
-        // to last entry in the pml4 table for our kernel.
+        // to last entry in the pml4 table for our kernel. the kernel is a
+        // position independent executable, so when we loaded it we slid it
+        // somewhere random in that entry and fixed up its relocations to
+        // match. the segments already know where they are going.
         //
         // what this means for the bootloader is that we need to massage the page
         // tables to reflect this without mapping ourselves to a different place (so
@@ -121,20 +314,237 @@ impl Kernel {
         // tables, and then call the entry function. once the kernel is running, we
         // will clean up memory there.
         //
-        // some temporary things about this implementation - we are currently only
-        // interested in getting the kernel called, so right now the kernel isn't
-        // getting the information it needs. eventually, I will need to figure out
-        // how to send it the information it craves, such as where it is in physical
-        // memory, it's size, and the whole uefi memory map, so it can make informed
-        // decisions.
+        // the information the kernel needs to make informed decisions about
+        // memory, such as where it is in physical memory, it's size, and the
+        // whole uefi memory map, is handed to it through the BootInfo struct
+        // that enter passes along. that struct lives in identity mapped
+        // LoaderData pages, so it has to stay identity mapped here too.
+        //
+        // to use our regular paging functionality, we need an allocator. that
+        // is the frame pool we allocated before exiting boot services.
+        //
+        // we also need a recursive mapping, since that's how the Mapper gets
+        // at the page tables. the firmware tables don't have one, and we don't
+        // want to touch them more than we have to, so we do the same trick
+        // ActivePageTable::with does. we make a fresh p4 table that maps itself
+        // recursively, then point the recursive entry of the firmware's p4 at
+        // it. now the Mapper sees our new table, and everything else stays the
+        // same until we switch to it.
+        let p4_frame = allocator.allocate_frame()
+            .expect("no frames left for the new p4 table");
+        let p4_addr = p4_frame.start_address();
+        unsafe {
+            // the firmware identity maps everything, so the physical address
+            // of the new table is also the virtual one.
+            let p4 = p4_addr as *mut u64;
+            for i in 0..512 {
+                *p4.offset(i) = 0;
+            }
+            *p4.offset(RECURSIVE_PAGE_PML4_INDEX as isize) = p4_addr as u64 |
+                (EntryFlags::PRESENT | EntryFlags::WRITABLE).bits();
 
-        // to use our regular paging functionality, we need an allocator.
+            // the firmware is allowed to keep its page tables read-only, so
+            // turn off write protection while we poke at its p4.
+            let cr0 = control_regs::cr0();
+            control_regs::cr0_write(cr0 & !Cr0::WRITE_PROTECT);
+            let uefi_p4 = control_regs::cr3().0 as *mut u64;
+            *uefi_p4.offset(RECURSIVE_PAGE_PML4_INDEX as isize) = p4_addr as u64 |
+                (EntryFlags::PRESENT | EntryFlags::WRITABLE).bits();
+            control_regs::cr0_write(cr0);
+        }
+        tlb::flush_all();
+
+        let mut mapper = unsafe { Mapper::new() };
+
+        // identity map everything in the memory map. that covers ourselves,
+        // our stack, the boot info, the firmware's runtime regions, and the
+        // page tables we are building right now. most of it is big and
+        // aligned enough for huge pages, which saves a lot of tables.
+        for region in boot_info.memory_map.regions() {
+            mapper.identity_map_range(region.start as usize,
+                                      (region.end - region.start) as usize,
+                                      EntryFlags::WRITABLE,
+                                      allocator);
+        }
+
+        // the framebuffer usually isn't in the memory map, since it's not
+        // memory, but the kernel needs it identity mapped too. if the firmware
+        // did list it, we already mapped it above, or at least some of it.
+        if let Some(fb) = boot_info.framebuffer() {
+            let (base, size) = (fb.base as usize, fb.size as usize);
+            let frames = || Frame::range_inclusive(Frame::containing_address(base),
+                                                   Frame::containing_address(base + size - 1));
+            let flags = EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE;
+            if frames().all(|frame| mapper.translate(frame.start_address()).is_none()) {
+                mapper.identity_map_range(base, size, flags, allocator);
+            } else {
+                for frame in frames() {
+                    if mapper.translate(frame.start_address()).is_none() {
+                        mapper.identity_map(frame, flags, allocator);
+                    }
+                }
+            }
+        }
+
+        // map the firmware's runtime regions where we told it they would be.
+        // we can't tell which bits of them are code, so none of them are
+        // no-execute.
+        for desc in runtime {
+            mapper.map_physical_range(UEFI_RUNTIME_OFFSET + desc.phys_start as usize,
+                                      desc.phys_start as usize,
+                                      desc.page_count as usize * PAGE_SIZE,
+                                      EntryFlags::WRITABLE,
+                                      allocator);
+        }
+
+        // turn on the no-execute bit so we can use it, and write protection,
+        // so the read-only segments are read-only for us too.
+        enable_nxe_bit();
+        enable_write_protect_bit();
+
+        // map each kernel segment at the address it was linked at, plus the
+        // slide, with the permissions it asked for.
+        for segment in &self.segments {
+            let start = Page::containing_address(segment.virt_start);
+            let end = Page::containing_address(segment.virt_start + segment.size - 1);
+            let frame = Frame::containing_address(segment.phys_start);
+            mapper.map_range_to(Page::range_inclusive(start, end), frame, segment.flags, allocator)
+                .expect("ran out of page table frames mapping the kernel");
+        }
+
+        // the relocations are all applied, so the relro part of the kernel
+        // only had to be writable until now.
+        if let Some((start, end)) = self.relro {
+            let segment = self.segments.iter()
+                .find(|s| start >= s.virt_start && end <= s.virt_start + s.size)
+                .expect("the relro region isn't inside a kernel segment");
+            let flags = segment.flags - EntryFlags::WRITABLE;
+            for page in Page::range_inclusive(Page::containing_address(start),
+                                              Page::containing_address(end - 1)) {
+                mapper.update_flags(page, flags);
+            }
+        }
+
+        // map the kernel stack, leaving the page below it unmapped, so running
+        // off the end of it faults instead of scribbling on something else.
+        let stack_start = Page::containing_address(KERNEL_STACK_OFFSET + PAGE_SIZE);
+        let stack_end = Page::containing_address(stack_top() - 1);
+        mapper.map_range_to(Page::range_inclusive(stack_start, stack_end),
+                            Frame::containing_address(self.stack_phys),
+                            EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE,
+                            allocator)
+            .expect("ran out of page table frames mapping the kernel stack");
+
+        // and finally, switch to the new table. since we are identity mapped in
+        // it the same way we were in the firmware's, execution just carries on.
+        unsafe {
+            control_regs::cr3_write(::x86_64::PhysicalAddress(p4_addr as u64));
+        }
     }
 
-    pub fn enter(self) -> ! {
-        // now turn this entry point into a callable function
+    /// enter switches to the kernel stack and calls the kernel entry point. it
+    /// has to be called after remap, since the stack is only mapped in the new
+    /// page tables.
+    pub fn enter(self, boot_info: &'static BootInfo) -> ! {
+        // we can't call the entry point as a function, since the compiler
+        // would want to use the stack we are leaving behind. instead, we switch
+        // stacks and call it ourselves. the bootloader is compiled for a
+        // windows-like target, but the kernel uses the system v calling
+        // convention, so the boot info goes in rdi. the stack top is page
+        // aligned, so the stack is 16 byte aligned at the call, like the kernel
+        // expects. zeroing rbp ends the chain of frames for backtraces.
         unsafe {
-            mem::transmute::<u64, EntryFunc>(self.entry)()
-        };
+            asm!("mov $0, %rsp
+                  xor %rbp, %rbp
+                  call *$1"
+                 :
+                 : "r"(stack_top()), "r"(self.entry), "{rdi}"(boot_info)
+                 : "memory"
+                 : "volatile");
+        }
+        unreachable!("the kernel returned");
+    }
+}
+
+/// stack_top returns the virtual address of the top of the kernel stack, which
+/// is what rsp starts out as.
+fn stack_top() -> usize {
+    KERNEL_STACK_OFFSET + PAGE_SIZE + KERNEL_STACK_SIZE
+}
+
+/// choose_slide picks a random offset for the kernel, a multiple of
+/// SLIDE_ALIGN that keeps all of it inside the kernel pml4 slot. if there is
+/// no entropy to be had, or the kernel isn't linked into that slot, it stays
+/// where it was linked.
+fn choose_slide(segments: &[Segment]) -> usize {
+    let virt_start = segments.iter().map(|s| s.virt_start).min().unwrap();
+    let virt_end = segments.iter().map(|s| s.virt_start + s.size).max().unwrap();
+    let slot_end = KERNEL_OFFSET + PML4_SIZE;
+    if virt_start < KERNEL_OFFSET || virt_end > slot_end {
+        warn!("kernel is linked at {:#x}-{:#x}, outside its pml4 slot, not randomizing its base",
+              virt_start, virt_end);
+        return 0;
+    }
+
+    let slides = (slot_end - virt_end) / SLIDE_ALIGN + 1;
+    match random::random_u64() {
+        Some(n) => (n as usize % slides) * SLIDE_ALIGN,
+        None => {
+            warn!("no source of entropy, not randomizing the kernel base");
+            0
+        },
+    }
+}
+
+/// relocate applies the kernel's dynamic relocations to its loaded segments,
+/// as if it had been linked slide bytes higher than it was, and moves the
+/// segments there. a static pie only has R_X86_64_RELATIVE relocations, which
+/// are just the link-time address of something that needs the slide added.
+fn relocate(kernel_elf: &elf::Elf, segments: &mut [Segment], slide: usize) -> Result<()> {
+    for rela in &kernel_elf.dynrelas {
+        if rela.r_type != reloc::R_X86_64_RELATIVE {
+            return Err(BootError::UnsupportedRelocation(rela.r_type));
+        }
+
+        let offset = rela.r_offset as usize;
+        let addr = segments.iter()
+            .filter_map(|s| s.phys_addr(offset, mem::size_of::<u64>()))
+            .next()
+            .ok_or(BootError::BadRelocation(offset))?;
+        let value = (rela.r_addend as i64 as u64).wrapping_add(slide as u64);
+        // the segments are in identity mapped LoaderData pages, and nothing
+        // says relocations are aligned.
+        unsafe { ptr::write_unaligned(addr as *mut u64, value) };
+    }
+
+    if slide != 0 {
+        info!("sliding the kernel by {:#x} ({} relocations)",
+              slide, kernel_elf.dynrelas.len());
+    }
+    for segment in segments.iter_mut() {
+        segment.virt_start += slide;
+    }
+
+    Ok(())
+}
+
+/// enable_nxe_bit sets the NXE bit in the EFER register, which makes the cpu
+/// honor the NO_EXECUTE bit in page table entries. without it, that bit is
+/// reserved, and setting it causes a page fault.
+fn enable_nxe_bit() {
+    use x86_64::registers::msr::{IA32_EFER, rdmsr, wrmsr};
+
+    let nxe_bit = 1 << 11;
+    unsafe {
+        let efer = rdmsr(IA32_EFER);
+        wrmsr(IA32_EFER, efer | nxe_bit);
+    }
+}
+
+/// enable_write_protect_bit sets the WRITE_PROTECT bit in the CR0 register,
+/// which makes the cpu honor read-only pages in kernel mode too.
+fn enable_write_protect_bit() {
+    unsafe {
+        control_regs::cr0_write(control_regs::cr0() | Cr0::WRITE_PROTECT);
     }
 }
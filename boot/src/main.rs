@@ -1,25 +1,57 @@
 //! the demos bootloader
 
+#![feature(alloc)]
+#![feature(asm)]
+#![feature(never_type)]
 #![no_std]
 #![no_main]
 
+#[macro_use]
+extern crate alloc;
+extern crate bootinfo;
 extern crate goblin;
 #[macro_use]
 extern crate log;
 extern crate memory;
+extern crate sha2;
 extern crate uefi;
 extern crate uefi_services;
 extern crate uefi_utils;
+extern crate x86_64;
 
+mod config;
 mod efi;
+mod error;
+mod info;
 mod kernel;
+mod manifest;
+mod menu;
+mod modules;
+mod pool;
+mod proto;
+mod random;
+mod runtime;
+mod timing;
+mod video;
 
+use alloc::vec::Vec;
+use bootinfo::{BootTimes, Module};
+use config::Config;
 use efi::Efi;
+use error::{BootError, Context, Result};
+use info::Handoff;
 use kernel::Kernel;
+use manifest::Manifest;
+use pool::FramePool;
+use runtime::RuntimeMap;
 use uefi::{Handle, Status, table};
 
 /// uefi_start is the entrypoint called by the uefi firmware. It is defined as
-/// the entrypoint as part of the target spec. It is responsible for setting up
+/// the entrypoint as part of the target spec. If anything goes wrong, it prints
+/// what happened and returns an error status to the firmware, so the firmware
+/// can try the next boot entry. If exiting boot services is what went wrong,
+/// the firmware may not be able to print anything or take control back, so it
+/// resets the machine instead. Otherwise, it is responsible for setting up
 /// all the stuff we need to actually start our kernel, loading it, exiting boot
 /// services cleanly, and then calling the kernel entrypoint. The kernel has to
 /// be compiled as a separate binary that exists at a known location on disk and
@@ -32,34 +64,140 @@ pub extern "C" fn uefi_start(
     handle: Handle,
     st: &'static table::SystemTable,
 ) -> Status {
+    match boot(handle, st) {
+        Ok(never) => never,
+        // none of boot services is safe to touch after a failed exit, so we
+        // can't print anything or wait for anyone to read it.
+        Err(BootError::ExitBootServices(status)) => runtime::reset(st, status),
+        Err(e) => {
+            error!("{}", e);
+            error!("giving control back to the firmware");
+            // give whoever is watching a chance to read that before the
+            // firmware moves on to the next boot entry.
+            st.boot.stall(5_000_000);
+            e.status()
+        },
+    }
+}
+
+/// boot does all the actual work of uefi_start. it only returns if something
+/// went wrong. anything that goes wrong before we exit boot services, including
+/// failing to exit them, comes back out of here as an error, so uefi_start can
+/// deal with it. after that, there is nobody to report it to, so we just panic.
+fn boot(handle: Handle, st: &'static table::SystemTable) -> Result<!> {
+    let mut times = BootTimes::empty();
+    times.start = timing::now();
+
     // initialize our runtime environment
-    let efi = Efi::init(handle, st);
+    let efi = Efi::init(handle, st)?;
+    times.tsc_frequency = timing::tsc_frequency(st);
 
     // welcome! to the bootloader
     info!("# DemOS #");
     info!("Image handle: {:?}", handle);
 
+    // everything we need lives on the same volume we were loaded from
+    let mut root = efi.boot_volume()?;
+
+    // read the configuration file, if there is one
+    let config = Config::load(&mut root);
+    log::set_max_level(config.log_level);
+    debug!("{:?}", config);
+    times.init = timing::now();
+
+    // figure out which kernel we are booting
+    let entry = &config.entries[menu::choose(st, &config.entries, config.timeout)];
+    info!("booting {}", entry.name);
+    times.menu = timing::now();
+
+    // read the digests the build wrote, so we can check we are booting what
+    // was actually built
+    let manifest = Manifest::load(&mut root);
+
     // load the kernel into memory
-    let kernel = Kernel::load();
+    let kernel = Kernel::load(&mut root, &entry.kernel, manifest.as_ref())?;
 
-    // grab the memory map from the firmware
-    let (key, desc) = efi.get_memory_map();
+    // and any modules that go along with it
+    let modules = entry.modules.iter()
+        .map(|path| modules::load(&mut root, path, manifest.as_ref()))
+        .collect::<Result<Vec<Module>>>()?;
+    times.kernel_load = timing::now();
+
+    // allocate somewhere to put the final memory map. this has to happen
+    // first, so there is room in it for everything we allocate after it.
+    let map_buffer = efi.alloc_memory_map_buffer()?;
+
+    // allocate the boot information we are going to hand to the kernel. this
+    // has to happen before we get the memory map, since it allocates.
+    let mut handoff = Handoff::alloc(efi::max_memory_regions(map_buffer))
+        .context("allocate the boot info")?;
+    handoff.set_kernel(kernel.extents());
+    handoff.set_kernel_segments(&kernel.segments())
+        .context("allocate the kernel segment list")?;
+    handoff.set_cmdline(&entry.cmdline)
+        .context("allocate the kernel command line")?;
+    handoff.set_modules(&modules)
+        .context("allocate the module list")?;
+    let mut runtime_map = RuntimeMap::alloc(efi::max_memory_regions(map_buffer))
+        .context("allocate the runtime memory map")?;
+
+    // find the firmware tables the kernel is going to want to look at. they
+    // live in memory the firmware keeps around, so we just pass them along.
+    match efi.acpi_rsdp() {
+        Some(rsdp) => handoff.set_acpi_rsdp(rsdp),
+        None => warn!("no ACPI RSDP in the configuration table"),
+    }
+    match efi.smbios() {
+        Some(smbios) => handoff.set_smbios(smbios),
+        None => warn!("no SMBIOS entry point in the configuration table"),
+    }
+
+    // set up the display for the kernel. this has to happen before we exit
+    // boot services, since it needs the graphics output protocol.
+    let framebuffer = video::init(config.video_mode);
+    if let Some(fb) = framebuffer {
+        handoff.set_framebuffer(fb)
+            .context("allocate the framebuffer info")?;
+    }
+
+    // once we exit boot services we can't allocate anything, so grab all the
+    // frames we are going to need for the kernel's page tables now.
+    let table_frames = efi.page_table_frames(map_buffer)? +
+        efi.runtime_page_table_frames(map_buffer)? + kernel.page_table_frames() +
+        framebuffer.map_or(0, |fb| pool::page_table_frames(fb.size as usize));
+    let mut frames = FramePool::alloc(table_frames)
+        .context("allocate frames for the page tables")?;
 
     // in my experience, using logging functions changes the memory map key,
-    // once we get the memory map, we don't log anymore. either way, once we
-    // exit boot services, we can't use the uefi logging functionality anyway.
+    // so once we start getting the final memory map, we don't log anymore.
+    // either way, once we exit boot services, we can't use the uefi logging
+    // functionality anyway.
+
+    // exit boot services, which grabs the final memory map from the firmware.
+    times.memory_map = timing::now();
+    let desc = efi.exit_boot_services(map_buffer)?;
+    times.exit_boot_services = timing::now();
 
-    // exit boot services.
-    efi.exit_boot_services(key);
+    // now that the memory map can't change anymore, record it for the kernel,
+    // picking out the regions the firmware still needs along the way.
+    handoff.set_memory_map(desc.inspect(|d| runtime_map.record(d)));
+
+    // tell the firmware where its runtime regions are going to be, so the
+    // kernel can keep using runtime services. this has to happen while we are
+    // still on the firmware's identity mapped page tables. if it fails, the
+    // kernel does without.
+    if let Some(addr) = runtime_map.virtualize(st) {
+        handoff.set_runtime_services(addr);
+    }
 
     // we are now fully in control of the system, and therefore responsible for
     // all i/o and memory functionality. it's time to remap the kernel to it's
     // expected location in the higher half of memory.
-    kernel.remap();
-
-    // start the kernel
-    kernel.enter();
+    handoff.set_times(times);
+    let boot_info = handoff.finish();
+    kernel.remap(boot_info, runtime_map.descriptors(), &mut frames);
+    boot_info.times.remap = timing::now();
 
-    // enter doesn't return!
-    // unreachable!();
+    // start the kernel. enter doesn't return!
+    kernel.enter(boot_info)
 }
@@ -0,0 +1,127 @@
+//! the manifest module checks the files we load against the digests the build
+//! wrote when it put them on the esp. the esp is a directory qemu pretends is a
+//! fat filesystem, and it's surprisingly easy to end up booting a stale or
+//! half-written kernel from it. this way we find out about it in the
+//! bootloader, instead of by debugging a kernel that isn't the one we built.
+//!
+//! the manifest lives at `/efi/demos/manifest`, and is just the output of
+//! `sha256sum`, run from the root of the esp.
+
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::str;
+use efi;
+use error::{BootError, Result};
+use sha2::{Digest, Sha256};
+use uefi::proto::media;
+
+/// MANIFEST_PATH is where we look for the manifest on the boot volume.
+pub const MANIFEST_PATH: &str = "/efi/demos/manifest";
+
+/// DIGEST_SIZE is the size of a sha-256 digest in bytes.
+pub const DIGEST_SIZE: usize = 32;
+
+#[derive(Debug)]
+pub struct Manifest {
+    digests: Vec<(String, [u8; DIGEST_SIZE])>,
+}
+
+impl Manifest {
+    /// load reads the manifest from the given directory, which should be the
+    /// root of the boot volume. if there isn't one, we can't check anything,
+    /// so we say so and carry on.
+    pub fn load(root: &mut media::File) -> Option<Self> {
+        let (buf, addr) = match efi::read_file(root, MANIFEST_PATH) {
+            Ok(file) => file,
+            Err(e) => {
+                warn!("{}, not checking kernel or module digests", e);
+                return None;
+            },
+        };
+
+        let manifest = match str::from_utf8(buf) {
+            Ok(text) => Some(Manifest::parse(text)),
+            Err(e) => {
+                warn!("manifest {} isn't valid utf-8, not checking digests: {}",
+                      MANIFEST_PATH, e);
+                None
+            },
+        };
+
+        let size = buf.len();
+        if let Err(status) = efi::free(addr, size) {
+            warn!("failed to free the manifest: {:?}", status);
+        }
+
+        manifest
+    }
+
+    /// parse parses the text of a manifest. every line is a hex digest,
+    /// whitespace, and a path, optionally with a `*` in front of it.
+    pub fn parse(text: &str) -> Self {
+        let mut digests = Vec::new();
+
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let mut parts = line.splitn(2, char::is_whitespace);
+            let digest = parts.next().and_then(parse_digest);
+            let path = parts.next().map(|path| path.trim().trim_left_matches('*'));
+            match (digest, path) {
+                (Some(digest), Some(path)) =>
+                    digests.push((normalize(path).to_string(), digest)),
+                _ => warn!("{}:{}: expected a sha-256 digest and a path",
+                           MANIFEST_PATH, n + 1),
+            }
+        }
+
+        Manifest { digests }
+    }
+
+    /// verify checks the contents of the file at the given path against its
+    /// digest in the manifest. a file that isn't in the manifest, or doesn't
+    /// match, is an error.
+    pub fn verify(&self, path: &str, data: &[u8]) -> Result<()> {
+        let expected = match self.digests.iter().find(|&&(ref p, _)| p == normalize(path)) {
+            Some(&(_, digest)) => digest,
+            None => return Err(BootError::NotInManifest(path.to_string())),
+        };
+
+        let mut actual = [0; DIGEST_SIZE];
+        actual.copy_from_slice(&Sha256::digest(data));
+
+        if actual != expected {
+            return Err(BootError::DigestMismatch {
+                path: path.into(),
+                expected,
+                actual,
+            });
+        }
+
+        debug!("{} matches its digest in the manifest", path);
+        Ok(())
+    }
+}
+
+/// normalize strips the bits off the front of a path that don't change which
+/// file it refers to, so the paths in the config and the manifest compare
+/// equal.
+fn normalize(path: &str) -> &str {
+    path.trim_left_matches("./").trim_left_matches('/')
+}
+
+/// parse_digest parses a sha-256 digest written out in hex.
+fn parse_digest(hex: &str) -> Option<[u8; DIGEST_SIZE]> {
+    if hex.len() != DIGEST_SIZE * 2 {
+        return None;
+    }
+
+    let mut digest = [0; DIGEST_SIZE];
+    for (i, byte) in digest.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(hex.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(digest)
+}
@@ -0,0 +1,97 @@
+//! the menu module draws a boot menu on the uefi console, so we can pick which
+//! kernel to boot when there is more than one in the config. the arrow keys
+//! move the selection, enter boots it, and if nobody touches anything before
+//! the timeout runs out, the first entry boots.
+
+use config::Entry;
+use core::fmt::Write;
+use uefi::Status;
+use uefi::proto::console::text::ScanCode;
+use uefi::table::SystemTable;
+
+/// POLL_INTERVAL is how long we wait between checking for key presses, in
+/// microseconds.
+const POLL_INTERVAL: usize = 10_000;
+
+/// POLLS_PER_SECOND is how many times we check for key presses every second.
+const POLLS_PER_SECOND: usize = 1_000_000 / POLL_INTERVAL;
+
+/// choose shows the boot menu and returns the index of the entry to boot. if
+/// there is only one entry, or the timeout is zero, there is nothing to choose,
+/// so it doesn't bother drawing anything. pressing any key stops the timeout.
+pub fn choose(st: &SystemTable, entries: &[Entry], timeout: usize) -> usize {
+    if entries.len() == 1 || timeout == 0 {
+        return 0;
+    }
+
+    let stdin = st.stdin();
+    if let Err(status) = stdin.reset(false) {
+        warn!("failed to reset stdin, skipping the boot menu: {:?}", status);
+        return 0;
+    }
+
+    let mut selected = 0;
+    let mut polls_left = Some(timeout * POLLS_PER_SECOND);
+
+    draw(st, entries, selected, Some(timeout));
+    loop {
+        match stdin.read_key() {
+            Ok(key) => {
+                polls_left = None;
+                if key.unicode_char == '\r' as u16 {
+                    return selected;
+                }
+                match key.scan_code {
+                    ScanCode::Up =>
+                        selected = (selected + entries.len() - 1) % entries.len(),
+                    ScanCode::Down =>
+                        selected = (selected + 1) % entries.len(),
+                    _ => {},
+                }
+                draw(st, entries, selected, None);
+            },
+            Err(Status::NotReady) => {},
+            Err(status) => {
+                warn!("failed to read a key, booting {}: {:?}",
+                      entries[selected].name, status);
+                return selected;
+            },
+        }
+
+        if let Some(polls) = polls_left {
+            if polls == 0 {
+                return selected;
+            }
+            // redraw once a second, so the countdown counts down
+            if polls % POLLS_PER_SECOND == 0 {
+                draw(st, entries, selected, Some(polls / POLLS_PER_SECOND));
+            }
+            polls_left = Some(polls - 1);
+        }
+
+        st.boot.stall(POLL_INTERVAL);
+    }
+}
+
+/// draw clears the screen and draws the menu, with the selected entry marked.
+/// if the timeout is still running, it says how many seconds are left.
+fn draw(st: &SystemTable, entries: &[Entry], selected: usize, seconds_left: Option<usize>) {
+    let stdout = st.stdout();
+    // failing to draw the menu isn't worth giving up over. the worst that can
+    // happen is we boot the first entry without anyone seeing the menu.
+    let _ = stdout.clear();
+
+    let _ = writeln!(stdout, "# DemOS #");
+    let _ = writeln!(stdout, "");
+    for (i, entry) in entries.iter().enumerate() {
+        let marker = if i == selected { ">" } else { " " };
+        let _ = writeln!(stdout, " {} {} ({})", marker, entry.name, entry.kernel);
+    }
+    let _ = writeln!(stdout, "");
+    let _ = match seconds_left {
+        Some(seconds) =>
+            writeln!(stdout, "booting {} in {}s", entries[selected].name, seconds),
+        None =>
+            writeln!(stdout, "up/down to choose, enter to boot"),
+    };
+}
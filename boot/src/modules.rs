@@ -0,0 +1,30 @@
+//! the modules module loads boot modules, which are files the kernel needs
+//! before it has a disk driver of its own to read them with, like the initrd
+//! or the userspace servers.
+
+use bootinfo::Module;
+use core::str;
+use efi;
+use error::{Context, Result};
+use manifest::Manifest;
+use uefi::proto::media;
+
+/// load reads the module at the given path into LoaderData pages, and
+/// describes it for the kernel. the name is copied into LoaderData pages too,
+/// so it survives the trip into the kernel. if there is a manifest, the module
+/// has to be in it and match its digest.
+pub fn load(root: &mut media::File, path: &str, manifest: Option<&Manifest>) -> Result<Module> {
+    let (data, addr) = efi::read_file(root, path)?;
+    if let Some(manifest) = manifest {
+        manifest.verify(path, data)?;
+    }
+    info!("loaded module {} ({} bytes) at {:#x}", path, data.len(), addr);
+
+    let name = efi::alloc(path.len())
+        .context("allocate memory for a module name")?;
+    name.copy_from_slice(path.as_bytes());
+
+    Ok(unsafe {
+        Module::new(str::from_utf8_unchecked(name), addr as u64, data.len() as u64)
+    })
+}
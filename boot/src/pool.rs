@@ -0,0 +1,55 @@
+//! the pool module implements a frame allocator for use after we exit boot
+//! services. once we do that, we can't ask the firmware for memory anymore, so
+//! we grab a block of pages up front and hand them out one at a time while we
+//! build the kernel's page tables.
+
+use efi;
+use memory::{Frame, FrameAllocator, PAGE_SIZE};
+use uefi;
+
+/// page_table_frames returns an upper bound on the number of page table frames
+/// it takes to map a contiguous range of the given size. it gets enough level
+/// 1 and level 2 tables to cover it, plus a couple extra in case it straddles
+/// table boundaries.
+pub fn page_table_frames(bytes: usize) -> usize {
+    let pages = bytes / PAGE_SIZE + 1;
+    pages / 512 + pages / (512 * 512) + 2
+}
+
+#[derive(Debug)]
+pub struct FramePool {
+    next: usize,
+    end: usize,
+}
+
+impl FramePool {
+    /// alloc allocates enough LoaderData pages from the firmware to hand out
+    /// the requested number of frames. since they are LoaderData, the kernel
+    /// knows not to reuse them until it's done with the page tables we build
+    /// out of them.
+    pub fn alloc(frames: usize) -> Result<Self, uefi::Status> {
+        let (_, addr) = efi::alloc_addr(frames * PAGE_SIZE)?;
+        trace!("allocated frame pool of {} frames at {:#x}", frames, addr);
+        Ok(FramePool {
+            next: addr,
+            end: addr + frames * PAGE_SIZE,
+        })
+    }
+}
+
+impl FrameAllocator for FramePool {
+    fn allocate_frame(&mut self) -> Option<Frame> {
+        if self.next < self.end {
+            let frame = Frame::containing_address(self.next);
+            self.next += PAGE_SIZE;
+            Some(frame)
+        } else {
+            None
+        }
+    }
+
+    fn deallocate_frame(&mut self, _frame: Frame) {
+        // the bootloader never frees anything, and everything it allocated
+        // gets handed to the kernel anyway.
+    }
+}
@@ -0,0 +1,127 @@
+//! the proto module defines uefi protocols that our underlying uefi library
+//! doesn't implement yet. they only define as much as we actually use.
+
+use core::ffi::c_void;
+use core::{mem, ptr};
+use uefi::{Guid, Handle, Status};
+use uefi::proto::Protocol;
+use uefi::table::boot::{MemoryDescriptor, MemoryType};
+
+/// MEMORY_DESCRIPTOR_VERSION is the version of the memory descriptor layout we
+/// hand back to the firmware.
+const MEMORY_DESCRIPTOR_VERSION: u32 = 1;
+
+/// RESET_COLD is the EfiResetCold reset type, which resets every piece of
+/// hardware, like pulling the plug.
+const RESET_COLD: u32 = 0;
+
+/// LoadedImage is the EFI_LOADED_IMAGE_PROTOCOL. the firmware installs it on
+/// the handle of every image it loads, including us, and it tells us things
+/// like which device we were loaded from and what options we were started
+/// with.
+#[repr(C)]
+pub struct LoadedImage {
+    revision: u32,
+    parent_handle: Handle,
+    system_table: *const c_void,
+
+    device_handle: Handle,
+    file_path: *const c_void,
+    _reserved: *const c_void,
+
+    load_options_size: u32,
+    load_options: *const c_void,
+
+    image_base: *const c_void,
+    image_size: u64,
+    image_code_type: MemoryType,
+    image_data_type: MemoryType,
+    unload: extern "win64" fn(Handle) -> usize,
+}
+
+impl LoadedImage {
+    /// device returns the handle of the device the image was loaded from.
+    pub fn device(&self) -> Handle {
+        self.device_handle
+    }
+}
+
+impl Protocol for LoadedImage {
+    const GUID: Guid = Guid::from_values(
+        0x5b1b31a1,
+        0x9562,
+        0x11d2,
+        0x8e3f,
+        [0x00, 0xa0, 0xc9, 0x69, 0x72, 0x3b],
+    );
+}
+
+/// Rng is the EFI_RNG_PROTOCOL. firmware that has one can hand out random
+/// numbers from whatever hardware source it knows about.
+#[repr(C)]
+pub struct Rng {
+    get_info: extern "win64" fn(&Rng, &mut usize, *mut Guid) -> Status,
+    get_rng: extern "win64" fn(&Rng, *const Guid, usize, *mut u8) -> Status,
+}
+
+impl Rng {
+    /// fill fills the buffer with random bytes, using the firmware's default
+    /// algorithm.
+    pub fn fill(&self, buf: &mut [u8]) -> Result<(), Status> {
+        match (self.get_rng)(self, ptr::null(), buf.len(), buf.as_mut_ptr()) {
+            Status::Success => Ok(()),
+            status => Err(status),
+        }
+    }
+}
+
+impl Protocol for Rng {
+    const GUID: Guid = Guid::from_values(
+        0x3152bca5,
+        0xeade,
+        0x433d,
+        0x862e,
+        [0xc0, 0x1c, 0xdc, 0x29, 0x1f, 0x44],
+    );
+}
+
+/// RuntimeServices is the EFI_RUNTIME_SERVICES table, up to the last function
+/// our underlying uefi library doesn't give us. the functions we don't use are
+/// just placeholders, to keep the layout right. the kernel has the whole thing.
+#[repr(C)]
+pub struct RuntimeServices {
+    header: [u8; 24],
+    get_time: usize,
+    set_time: usize,
+    get_wakeup_time: usize,
+    set_wakeup_time: usize,
+    set_virtual_address_map: extern "win64" fn(usize, usize, u32, *const MemoryDescriptor) -> Status,
+    convert_pointer: usize,
+    get_variable: usize,
+    get_next_variable_name: usize,
+    set_variable: usize,
+    get_next_high_monotonic_count: usize,
+    reset_system: extern "win64" fn(u32, Status, usize, *const u8) -> !,
+}
+
+impl RuntimeServices {
+    /// set_virtual_address_map tells the firmware where the runtime regions
+    /// in the given map are going to be mapped from now on. it can only be
+    /// called once, after exiting boot services, while everything is still
+    /// identity mapped.
+    pub unsafe fn set_virtual_address_map(&self, map: &[MemoryDescriptor]) -> Result<(), Status> {
+        let desc_size = mem::size_of::<MemoryDescriptor>();
+        match (self.set_virtual_address_map)(map.len() * desc_size, desc_size,
+                                             MEMORY_DESCRIPTOR_VERSION, map.as_ptr()) {
+            Status::Success => Ok(()),
+            status => Err(status),
+        }
+    }
+
+    /// reset_system asks the firmware for a cold reset, handing it status as
+    /// the reason. unlike almost everything else, it still works after we
+    /// exit boot services, and it doesn't return.
+    pub fn reset_system(&self, status: Status) -> ! {
+        (self.reset_system)(RESET_COLD, status, 0, ptr::null())
+    }
+}
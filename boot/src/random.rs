@@ -0,0 +1,63 @@
+//! the random module finds us some entropy, which is mostly for picking where
+//! the kernel goes. we ask the firmware first, since it knows what hardware
+//! there is, then fall back to rdrand ourselves.
+
+use core::arch::x86_64::{__cpuid, _rdrand64_step};
+use core::mem;
+use proto::Rng;
+use uefi_utils::proto::find_protocol;
+
+/// RDRAND_RETRIES is how many times we ask rdrand for a number before giving
+/// up. intel says ten is enough unless the hardware is actually broken.
+const RDRAND_RETRIES: usize = 10;
+
+/// random_u64 returns a random number, or None if there is no source of
+/// entropy on this machine.
+pub fn random_u64() -> Option<u64> {
+    firmware_random().or_else(rdrand)
+}
+
+/// firmware_random gets a random number from the uefi rng protocol.
+fn firmware_random() -> Option<u64> {
+    let rng_ptr = find_protocol::<Rng>()?;
+    let rng = unsafe { rng_ptr.as_ref() };
+
+    let mut buf = [0u8; 8];
+    match rng.fill(&mut buf) {
+        Ok(()) => Some(unsafe { mem::transmute::<[u8; 8], u64>(buf) }),
+        Err(status) => {
+            warn!("firmware rng failed: {:?}", status);
+            None
+        },
+    }
+}
+
+/// rdrand gets a random number from the cpu, if it supports rdrand.
+fn rdrand() -> Option<u64> {
+    // cpuid leaf 1, ecx bit 30 says whether rdrand is there
+    let has_rdrand = unsafe { __cpuid(1).ecx & (1 << 30) != 0 };
+    if !has_rdrand {
+        return None;
+    }
+
+    for _ in 0..RDRAND_RETRIES {
+        if let Some(n) = unsafe { rdrand_step() } {
+            return Some(n);
+        }
+    }
+
+    warn!("rdrand didn't give us a number after {} tries", RDRAND_RETRIES);
+    None
+}
+
+/// rdrand_step asks rdrand for a number once. it can fail if the hardware is
+/// out of entropy for the moment.
+#[target_feature(enable = "rdrand")]
+unsafe fn rdrand_step() -> Option<u64> {
+    let mut n = 0;
+    if _rdrand64_step(&mut n) == 1 {
+        Some(n)
+    } else {
+        None
+    }
+}
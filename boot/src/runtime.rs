@@ -0,0 +1,75 @@
+//! the runtime module keeps uefi runtime services working for the kernel. the
+//! firmware needs its runtime regions mapped somewhere after we are gone, and
+//! it needs to know where, which is what SetVirtualAddressMap is for. we map
+//! every region with the runtime attribute at UEFI_RUNTIME_OFFSET plus its
+//! physical address, out of the kernel's way.
+
+use core::{mem, slice};
+use efi;
+use memory::map::UEFI_RUNTIME_OFFSET;
+use proto::RuntimeServices;
+use uefi::{self, table};
+use uefi::table::boot::{MemoryAttribute, MemoryDescriptor};
+
+pub struct RuntimeMap {
+    descriptors: &'static mut [MemoryDescriptor],
+    len: usize,
+}
+
+impl RuntimeMap {
+    /// alloc allocates room for max_regions runtime descriptors. it has to be
+    /// called before exiting boot services, since the final memory map is
+    /// what fills it in.
+    pub fn alloc(max_regions: usize) -> Result<Self, uefi::Status> {
+        let buf = efi::alloc(max_regions * mem::size_of::<MemoryDescriptor>())?;
+        let descriptors = unsafe {
+            slice::from_raw_parts_mut(buf.as_mut_ptr() as *mut MemoryDescriptor, max_regions)
+        };
+        Ok(RuntimeMap { descriptors, len: 0 })
+    }
+
+    /// record keeps a copy of the descriptor if the firmware needs the region
+    /// at runtime, with the virtual address it's going to end up at. it's
+    /// called after exit_boot_services, so it can't allocate or log.
+    pub fn record(&mut self, desc: &MemoryDescriptor) {
+        if !desc.att.contains(MemoryAttribute::RUNTIME) || self.len == self.descriptors.len() {
+            return;
+        }
+
+        let mut desc = *desc;
+        desc.virt_start = UEFI_RUNTIME_OFFSET as u64 + desc.phys_start;
+        self.descriptors[self.len] = desc;
+        self.len += 1;
+    }
+
+    /// descriptors returns the runtime regions recorded so far.
+    pub fn descriptors(&self) -> &[MemoryDescriptor] {
+        &self.descriptors[..self.len]
+    }
+
+    /// virtualize hands the runtime regions to SetVirtualAddressMap, and
+    /// returns the address the runtime services table is going to be at. it
+    /// has to be called after exit_boot_services and before we switch page
+    /// tables. if it fails, the kernel just has to do without runtime
+    /// services, so it returns None instead of an error.
+    pub fn virtualize(&self, st: &table::SystemTable) -> Option<u64> {
+        let runtime = st.runtime as *const _ as *const RuntimeServices;
+        let result = unsafe {
+            (*runtime).set_virtual_address_map(self.descriptors())
+        };
+
+        // the table itself lives in a runtime region, so it moves along with
+        // the rest of them.
+        result.ok().map(|()| UEFI_RUNTIME_OFFSET as u64 + runtime as u64)
+    }
+}
+
+/// reset resets the machine through runtime services, with status as the
+/// reason. it's the only way out once exiting boot services has gone wrong,
+/// since the firmware might not be around to return to anymore. it has to be
+/// called before SetVirtualAddressMap, while the table is still where the
+/// system table says it is.
+pub fn reset(st: &table::SystemTable, status: uefi::Status) -> ! {
+    let runtime = st.runtime as *const _ as *const RuntimeServices;
+    unsafe { (*runtime).reset_system(status) }
+}
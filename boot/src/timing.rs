@@ -0,0 +1,24 @@
+//! the timing module time stamps the phases of the boot with the tsc, so we can
+//! tell when booting gets slower.
+
+use core::arch::x86_64::_rdtsc;
+use uefi::table::SystemTable;
+
+/// CALIBRATION_PERIOD is how long we watch the tsc for to work out how fast it
+/// goes, in microseconds. longer is more accurate, but it all adds up to boot
+/// time.
+const CALIBRATION_PERIOD: usize = 10_000;
+
+/// now reads the time stamp counter.
+pub fn now() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+/// tsc_frequency works out how many times a second the tsc ticks, by counting
+/// ticks while the firmware stalls for a known amount of time.
+pub fn tsc_frequency(st: &SystemTable) -> u64 {
+    let start = now();
+    st.boot.stall(CALIBRATION_PERIOD);
+    let ticks = now() - start;
+    ticks * (1_000_000 / CALIBRATION_PERIOD as u64)
+}
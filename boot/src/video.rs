@@ -0,0 +1,75 @@
+//! the video module sets up the graphics output protocol, so the kernel has a
+//! framebuffer to draw on once the firmware console is gone.
+
+use bootinfo::{FrameBuffer, PixelFormat};
+use uefi::proto::console::gop::{self, GraphicsOutput};
+use uefi_utils::proto::find_protocol;
+
+/// init finds the graphics output protocol, switches it to the preferred
+/// resolution if there is one, and describes the resulting framebuffer. it
+/// returns None if there is no graphics output, or if it doesn't have a linear
+/// framebuffer we can use.
+pub fn init(preferred: Option<(usize, usize)>) -> Option<FrameBuffer> {
+    // there is normally only one graphics output, and if there are more, the
+    // first one is as good as any.
+    let mut gop_ptr = match find_protocol::<GraphicsOutput>() {
+        Some(gop_ptr) => gop_ptr,
+        None => {
+            warn!("no graphics output protocol, the kernel won't have a display");
+            return None;
+        },
+    };
+    let gop = unsafe { gop_ptr.as_mut() };
+
+    if let Some((width, height)) = preferred {
+        let mode = gop.modes().find(|mode| {
+            let info = mode.info();
+            info.resolution() == (width, height) &&
+                pixel_format(info.pixel_format()).is_some()
+        });
+        match mode {
+            Some(mode) => if let Err(status) = gop.set_mode(&mode) {
+                warn!("failed to set {}x{} graphics mode, keeping the current one: {:?}",
+                      width, height, status);
+            },
+            None => warn!("no {}x{} graphics mode, keeping the current one",
+                          width, height),
+        }
+    }
+
+    let info = gop.current_mode_info();
+    let (width, height) = info.resolution();
+    let format = match pixel_format(info.pixel_format()) {
+        Some(format) => format,
+        None => {
+            warn!("graphics mode {:?} has no usable framebuffer",
+                  info.pixel_format());
+            return None;
+        },
+    };
+
+    let mut fb = gop.frame_buffer();
+    let framebuffer = FrameBuffer {
+        base: fb.as_mut_ptr() as u64,
+        size: fb.size() as u64,
+        width: width as u32,
+        height: height as u32,
+        stride: info.stride() as u32,
+        format,
+    };
+    info!("framebuffer: {}x{} {:?} at {:#x}",
+          width, height, format, framebuffer.base);
+
+    Some(framebuffer)
+}
+
+/// pixel_format converts the uefi pixel format into ours. the formats that
+/// don't have a framebuffer, or need a bitmask to make sense of, aren't worth
+/// the trouble yet.
+fn pixel_format(format: gop::PixelFormat) -> Option<PixelFormat> {
+    match format {
+        gop::PixelFormat::RGB => Some(PixelFormat::Rgb),
+        gop::PixelFormat::BGR => Some(PixelFormat::Bgr),
+        _ => None,
+    }
+}
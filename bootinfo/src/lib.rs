@@ -0,0 +1,515 @@
+//! bootinfo defines the structure the bootloader uses to hand information off
+//! to the kernel. it is shared between the `boot` crate, which fills it in, and
+//! the kernel, which reads it. everything in here is `#[repr(C)]` so that the
+//! layout is well defined even though the two sides are compiled for different
+//! targets.
+//!
+//! the bootloader allocates the BootInfo struct and everything it points at in
+//! LoaderData pages, which the kernel must not reclaim until it is done with
+//! the information in them. all the pointers are physical addresses, which the
+//! bootloader keeps identity mapped when it jumps into the kernel.
+
+#![feature(const_fn)]
+#![no_std]
+
+use core::{ptr, slice, str};
+
+/// BOOT_INFO_MAGIC is the first field of the BootInfo struct. the kernel checks
+/// it to make sure it was actually handed a BootInfo and not garbage. it's
+/// "DemOSBI\0" in little-endian ascii.
+pub const BOOT_INFO_MAGIC: u64 = 0x0049_4253_4f6d_6544;
+
+/// BOOT_INFO_VERSION is the version of the layout of the BootInfo struct. it
+/// must be bumped every time the layout of anything in this crate changes, so
+/// the kernel can refuse to boot with a mismatched bootloader instead of
+/// reading fields from the wrong place.
+pub const BOOT_INFO_VERSION: u32 = 9;
+
+/// BootInfo is the structure passed as the first argument to `kernel_main`.
+#[repr(C)]
+#[derive(Debug)]
+pub struct BootInfo {
+    /// magic is always BOOT_INFO_MAGIC.
+    pub magic: u64,
+    /// version is always BOOT_INFO_VERSION for the bootloader that filled it
+    /// in.
+    pub version: u32,
+    /// memory_map is the firmware memory map, converted into our own region
+    /// type.
+    pub memory_map: MemoryMap,
+    /// kernel describes where the kernel ended up, both physically and
+    /// virtually.
+    pub kernel: KernelExtents,
+    /// kernel_segments are where each loadable segment of the kernel ended
+    /// up, and what it is allowed to do.
+    pub kernel_segments: SegmentList,
+    /// framebuffer is the physical address of a FrameBuffer struct, or null if
+    /// the bootloader didn't find one.
+    pub framebuffer: *const FrameBuffer,
+    /// acpi_rsdp is the physical address of the ACPI root system description
+    /// pointer, or zero if the bootloader didn't find one.
+    pub acpi_rsdp: u64,
+    /// smbios is the physical address of the SMBIOS entry point, or zero if
+    /// the bootloader didn't find one. it's the 64-bit SMBIOS 3 entry point if
+    /// the firmware has one, and the 32-bit one otherwise.
+    pub smbios: u64,
+    /// runtime_services is the virtual address of the uefi runtime services
+    /// table, or zero if the firmware wouldn't switch to virtual addressing.
+    /// the bootloader maps every region the firmware needs at runtime at a
+    /// fixed offset from its physical address, and has already told the
+    /// firmware where they are, so the table is only usable with that mapping.
+    pub runtime_services: u64,
+    /// cmdline is the kernel command line from the bootloader configuration.
+    pub cmdline: CmdLine,
+    /// modules are the files the bootloader loaded alongside the kernel.
+    pub modules: ModuleList,
+    /// times are when each phase of the boot finished, for keeping an eye on
+    /// how long booting takes.
+    pub times: BootTimes,
+}
+
+impl BootInfo {
+    /// new returns an empty BootInfo with the magic and version filled in.
+    pub const fn new() -> Self {
+        BootInfo {
+            magic: BOOT_INFO_MAGIC,
+            version: BOOT_INFO_VERSION,
+            memory_map: MemoryMap::empty(),
+            kernel: KernelExtents::empty(),
+            kernel_segments: SegmentList::empty(),
+            framebuffer: ptr::null(),
+            acpi_rsdp: 0,
+            smbios: 0,
+            runtime_services: 0,
+            cmdline: CmdLine::empty(),
+            modules: ModuleList::empty(),
+            times: BootTimes::empty(),
+        }
+    }
+
+    /// is_valid checks the magic number and version, which is the only way
+    /// the kernel has of knowing whether the bootloader and itself agree on
+    /// the layout of this struct.
+    pub fn is_valid(&self) -> bool {
+        self.magic == BOOT_INFO_MAGIC && self.version == BOOT_INFO_VERSION
+    }
+
+    /// framebuffer returns the framebuffer information, if there is any.
+    pub fn framebuffer(&self) -> Option<&FrameBuffer> {
+        unsafe { self.framebuffer.as_ref() }
+    }
+
+    /// acpi_rsdp returns the physical address of the ACPI RSDP, if there is
+    /// one.
+    pub fn acpi_rsdp(&self) -> Option<u64> {
+        if self.acpi_rsdp == 0 {
+            None
+        } else {
+            Some(self.acpi_rsdp)
+        }
+    }
+
+    /// smbios returns the physical address of the SMBIOS entry point, if
+    /// there is one.
+    pub fn smbios(&self) -> Option<u64> {
+        if self.smbios == 0 {
+            None
+        } else {
+            Some(self.smbios)
+        }
+    }
+
+    /// runtime_services returns the virtual address of the uefi runtime
+    /// services table, if there is one.
+    pub fn runtime_services(&self) -> Option<u64> {
+        if self.runtime_services == 0 {
+            None
+        } else {
+            Some(self.runtime_services)
+        }
+    }
+}
+
+/// MemoryMap is a counted pointer to an array of MemoryRegions.
+#[repr(C)]
+#[derive(Debug)]
+pub struct MemoryMap {
+    regions: *const MemoryRegion,
+    len: usize,
+}
+
+impl MemoryMap {
+    /// empty returns a memory map with no regions in it.
+    pub const fn empty() -> Self {
+        MemoryMap {
+            regions: ptr::null(),
+            len: 0,
+        }
+    }
+
+    /// from_raw_parts makes a memory map out of an array of regions. it is
+    /// unsafe because the caller has to make sure that the array lives as
+    /// long as the BootInfo that holds it.
+    pub unsafe fn from_raw_parts(regions: *const MemoryRegion, len: usize) -> Self {
+        MemoryMap { regions, len }
+    }
+
+    /// regions returns the memory regions as a slice.
+    pub fn regions(&self) -> &[MemoryRegion] {
+        if self.regions.is_null() {
+            &[]
+        } else {
+            unsafe { slice::from_raw_parts(self.regions, self.len) }
+        }
+    }
+}
+
+/// CmdLine is a counted pointer to the utf-8 bytes of the kernel command line.
+#[repr(C)]
+#[derive(Debug)]
+pub struct CmdLine {
+    bytes: *const u8,
+    len: usize,
+}
+
+impl CmdLine {
+    /// empty returns an empty command line.
+    pub const fn empty() -> Self {
+        CmdLine {
+            bytes: ptr::null(),
+            len: 0,
+        }
+    }
+
+    /// from_raw_parts makes a command line out of a byte array. it is unsafe
+    /// because the caller has to make sure that the array lives as long as the
+    /// BootInfo that holds it, and that it is valid utf-8.
+    pub unsafe fn from_raw_parts(bytes: *const u8, len: usize) -> Self {
+        CmdLine { bytes, len }
+    }
+
+    /// as_str returns the command line as a string.
+    pub fn as_str(&self) -> &str {
+        if self.bytes.is_null() {
+            ""
+        } else {
+            unsafe {
+                str::from_utf8_unchecked(slice::from_raw_parts(self.bytes, self.len))
+            }
+        }
+    }
+}
+
+/// ModuleList is a counted pointer to an array of Modules.
+#[repr(C)]
+#[derive(Debug)]
+pub struct ModuleList {
+    modules: *const Module,
+    len: usize,
+}
+
+impl ModuleList {
+    /// empty returns a module list with no modules in it.
+    pub const fn empty() -> Self {
+        ModuleList {
+            modules: ptr::null(),
+            len: 0,
+        }
+    }
+
+    /// from_raw_parts makes a module list out of an array of modules. it is
+    /// unsafe because the caller has to make sure that the array lives as
+    /// long as the BootInfo that holds it.
+    pub unsafe fn from_raw_parts(modules: *const Module, len: usize) -> Self {
+        ModuleList { modules, len }
+    }
+
+    /// modules returns the modules as a slice.
+    pub fn modules(&self) -> &[Module] {
+        if self.modules.is_null() {
+            &[]
+        } else {
+            unsafe { slice::from_raw_parts(self.modules, self.len) }
+        }
+    }
+}
+
+/// Module is a file the bootloader loaded into memory for the kernel, like an
+/// initrd or a userspace server.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct Module {
+    name: *const u8,
+    name_len: usize,
+    /// phys_start is the physical address the module was loaded at.
+    pub phys_start: u64,
+    /// size is the size of the module in bytes.
+    pub size: u64,
+}
+
+// modules are read-only once the bootloader hands them off, so sharing them
+// between threads is fine, even though they are made of raw pointers.
+unsafe impl Send for Module {}
+unsafe impl Sync for Module {}
+
+impl Module {
+    /// new makes a module description. it is unsafe because the caller has to
+    /// make sure that the name lives as long as the BootInfo that holds the
+    /// module.
+    pub unsafe fn new(name: &str, phys_start: u64, size: u64) -> Self {
+        Module {
+            name: name.as_ptr(),
+            name_len: name.len(),
+            phys_start,
+            size,
+        }
+    }
+
+    /// name returns the name of the module, which is the path it was loaded
+    /// from.
+    pub fn name(&self) -> &str {
+        unsafe {
+            str::from_utf8_unchecked(slice::from_raw_parts(self.name, self.name_len))
+        }
+    }
+
+    /// data returns the contents of the module. this relies on the module
+    /// still being identity mapped.
+    pub fn data(&self) -> &[u8] {
+        unsafe {
+            slice::from_raw_parts(self.phys_start as *const u8, self.size as usize)
+        }
+    }
+}
+
+/// MemoryRegion is a single contiguous range of physical memory.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryRegion {
+    /// start is the physical address of the beginning of the region.
+    pub start: u64,
+    /// end is the physical address one past the end of the region.
+    pub end: u64,
+    /// kind describes what the region can be used for.
+    pub kind: MemoryRegionKind,
+}
+
+impl MemoryRegion {
+    /// size returns the size of the region in bytes.
+    pub fn size(&self) -> u64 {
+        self.end - self.start
+    }
+}
+
+/// MemoryRegionKind is our own simplified version of the uefi memory types.
+/// the kernel mostly cares about whether it can use a region or not, and if
+/// not, who is using it.
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryRegionKind {
+    /// usable memory that nobody is using.
+    Usable,
+    /// memory the bootloader used for the kernel and the structures it is
+    /// handing off. it becomes usable once the kernel is done with it.
+    Bootloader,
+    /// memory the firmware used for boot services. it is usable after
+    /// exit_boot_services, which has already happened by the time the kernel
+    /// sees this.
+    BootServices,
+    /// memory the firmware needs to keep around for runtime services.
+    RuntimeServices,
+    /// memory holding ACPI tables. it becomes usable once the kernel is done
+    /// reading the tables.
+    AcpiReclaimable,
+    /// memory the firmware wants saved across sleep states.
+    AcpiNvs,
+    /// memory mapped i/o.
+    Mmio,
+    /// memory that is broken.
+    Unusable,
+    /// memory we don't understand and shouldn't touch.
+    Reserved,
+}
+
+/// KernelExtents describes the range of memory the kernel image occupies.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct KernelExtents {
+    /// phys_start is the physical address the kernel was loaded at.
+    pub phys_start: u64,
+    /// phys_end is the physical address one past the end of the kernel.
+    pub phys_end: u64,
+    /// virt_start is the lowest virtual address the kernel is mapped at.
+    pub virt_start: u64,
+    /// virt_end is the virtual address one past the end of the kernel.
+    pub virt_end: u64,
+    /// slide is how far the bootloader moved the kernel from the address it
+    /// was linked at. it's zero if the kernel wasn't moved.
+    pub slide: u64,
+}
+
+impl KernelExtents {
+    /// empty returns extents with everything set to zero.
+    pub const fn empty() -> Self {
+        KernelExtents {
+            phys_start: 0,
+            phys_end: 0,
+            virt_start: 0,
+            virt_end: 0,
+            slide: 0,
+        }
+    }
+}
+
+/// SegmentList is a counted pointer to an array of KernelSegments.
+#[repr(C)]
+#[derive(Debug)]
+pub struct SegmentList {
+    segments: *const KernelSegment,
+    len: usize,
+}
+
+impl SegmentList {
+    /// empty returns a segment list with no segments in it.
+    pub const fn empty() -> Self {
+        SegmentList {
+            segments: ptr::null(),
+            len: 0,
+        }
+    }
+
+    /// from_raw_parts makes a segment list out of an array of segments. it is
+    /// unsafe because the caller has to make sure that the array lives as
+    /// long as the BootInfo that holds it.
+    pub unsafe fn from_raw_parts(segments: *const KernelSegment, len: usize) -> Self {
+        SegmentList { segments, len }
+    }
+
+    /// segments returns the segments as a slice.
+    pub fn segments(&self) -> &[KernelSegment] {
+        if self.segments.is_null() {
+            &[]
+        } else {
+            unsafe { slice::from_raw_parts(self.segments, self.len) }
+        }
+    }
+}
+
+/// KernelSegment describes one loadable segment of the kernel. the bootloader
+/// doesn't load the kernel contiguously in physical memory, so the kernel needs
+/// these to map itself.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct KernelSegment {
+    /// phys_start is the physical address the segment was loaded at.
+    pub phys_start: u64,
+    /// virt_start is the virtual address the segment is mapped at.
+    pub virt_start: u64,
+    /// size is the size of the segment in memory, in bytes.
+    pub size: u64,
+    /// flags say what the segment is allowed to do, as a combination of the
+    /// SEGMENT_ constants.
+    pub flags: u32,
+}
+
+/// SEGMENT_WRITABLE is set on kernel segments that can be written to.
+pub const SEGMENT_WRITABLE: u32 = 1 << 0;
+/// SEGMENT_EXECUTABLE is set on kernel segments that have code in them.
+pub const SEGMENT_EXECUTABLE: u32 = 1 << 1;
+
+/// BootTimes records the time stamp counter at the end of each phase of the
+/// bootloader. they only mean anything relative to each other, and to the tsc
+/// once the kernel is running.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct BootTimes {
+    /// tsc_frequency is how many times a second the tsc ticks, as measured by
+    /// the bootloader, or zero if it couldn't tell.
+    pub tsc_frequency: u64,
+    /// start is when the bootloader started.
+    pub start: u64,
+    /// init is when the firmware was set up and the config was read.
+    pub init: u64,
+    /// menu is when an entry was picked from the boot menu. this phase is
+    /// mostly waiting for someone to press a key.
+    pub menu: u64,
+    /// kernel_load is when the kernel and its modules were loaded.
+    pub kernel_load: u64,
+    /// memory_map is when everything was allocated and we were ready to get
+    /// the final memory map.
+    pub memory_map: u64,
+    /// exit_boot_services is when the firmware let go of the machine.
+    pub exit_boot_services: u64,
+    /// remap is when the kernel's page tables were switched to, right before
+    /// jumping into the kernel.
+    pub remap: u64,
+}
+
+impl BootTimes {
+    /// empty returns boot times with everything set to zero.
+    pub const fn empty() -> Self {
+        BootTimes {
+            tsc_frequency: 0,
+            start: 0,
+            init: 0,
+            menu: 0,
+            kernel_load: 0,
+            memory_map: 0,
+            exit_boot_services: 0,
+            remap: 0,
+        }
+    }
+
+    /// phases returns the name of each phase of the boot, along with how many
+    /// tsc ticks it took.
+    pub fn phases(&self) -> [(&'static str, u64); 6] {
+        [
+            ("init", self.init.saturating_sub(self.start)),
+            ("menu", self.menu.saturating_sub(self.init)),
+            ("kernel load", self.kernel_load.saturating_sub(self.menu)),
+            ("memory map", self.memory_map.saturating_sub(self.kernel_load)),
+            ("exit boot services", self.exit_boot_services.saturating_sub(self.memory_map)),
+            ("remap", self.remap.saturating_sub(self.exit_boot_services)),
+        ]
+    }
+
+    /// micros converts tsc ticks into microseconds, if we know how fast the
+    /// tsc is.
+    pub fn micros(&self, ticks: u64) -> Option<u64> {
+        if self.tsc_frequency == 0 {
+            None
+        } else {
+            Some((ticks as u128 * 1_000_000 / self.tsc_frequency as u128) as u64)
+        }
+    }
+}
+
+/// FrameBuffer describes a linear framebuffer the kernel can draw to without
+/// any help from the firmware.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct FrameBuffer {
+    /// base is the physical address of the framebuffer.
+    pub base: u64,
+    /// size is the size of the framebuffer in bytes.
+    pub size: u64,
+    /// width is the horizontal resolution in pixels.
+    pub width: u32,
+    /// height is the vertical resolution in pixels.
+    pub height: u32,
+    /// stride is the number of pixels in a row of the framebuffer, which may
+    /// be more than the width.
+    pub stride: u32,
+    /// format is the layout of each pixel.
+    pub format: PixelFormat,
+}
+
+/// PixelFormat is the layout of a single 32-bit pixel in the framebuffer.
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PixelFormat {
+    /// red in the lowest byte, then green, then blue, then a reserved byte.
+    Rgb,
+    /// blue in the lowest byte, then green, then red, then a reserved byte.
+    Bgr,
+}
@@ -0,0 +1,220 @@
+//! bitmap_frame_allocator keeps track of every frame of physical memory with
+//! one bit each, set if the frame is in use. unlike the area allocator it
+//! replaced, it can take frames back, and it can find runs of contiguous
+//! frames for things like dma buffers that need them.
+//!
+//! we don't have a heap yet when the allocator is made, so the bitmap itself
+//! is carved out of the first usable region with room for it. it's accessed
+//! at its physical address, which relies on whatever booted us identity
+//! mapping physical memory, and on paging::init keeping that mapping around.
+
+use core::slice;
+use kernel_image::KernelImage;
+use region::MemoryRegion;
+use super::{Frame, FrameAllocator, PAGE_SIZE};
+
+/// MAX_AREAS is how many usable regions we look at while seeding the bitmap.
+/// any past this are left marked as used, which just means we don't use some
+/// memory.
+const MAX_AREAS: usize = 64;
+
+/// BITS is how many frames one word of the bitmap keeps track of.
+const BITS: usize = 64;
+
+pub struct BitmapFrameAllocator {
+    /// bitmap has a bit for every frame below frames, set if it's in use.
+    bitmap: &'static mut [u64],
+    /// frames is how many frames the bitmap covers, starting from frame 0.
+    frames: usize,
+    /// free is how many frames are currently free.
+    free: usize,
+    /// next is where allocate_frame starts looking. every frame below it is
+    /// in use.
+    next: usize,
+}
+
+impl BitmapFrameAllocator {
+    /// new makes an allocator that hands out frames from the usable regions.
+    /// every frame the kernel image is in is marked as used, as is everything
+    /// that isn't in a usable region, like the memory the bootloader left
+    /// things in for us. it returns None if none of the usable regions have
+    /// room for the bitmap.
+    pub fn new<I>(kernel: &KernelImage, regions: I) -> Option<BitmapFrameAllocator>
+        where I: IntoIterator<Item = MemoryRegion>
+    {
+        let mut areas = [None; MAX_AREAS];
+        let usable = regions.into_iter().filter(|region| region.is_usable());
+        for (area, region) in areas.iter_mut().zip(usable) {
+            *area = Some(region);
+        }
+        let areas = areas.iter().filter_map(|area| *area);
+
+        let frames = areas.clone()
+            .map(|area| area.end_frame().number)
+            .max()
+            .unwrap_or(0);
+        let words = (frames + BITS - 1) / BITS;
+        let bitmap_frames = (words * 8 + PAGE_SIZE - 1) / PAGE_SIZE;
+
+        let bitmap_start = areas.clone()
+            .filter_map(|area| find_room(kernel, &area, bitmap_frames))
+            .next()?;
+        let bitmap = unsafe {
+            slice::from_raw_parts_mut((bitmap_start * PAGE_SIZE) as *mut u64, words)
+        };
+        for word in bitmap.iter_mut() {
+            *word = !0;
+        }
+
+        let mut allocator = BitmapFrameAllocator {
+            bitmap: bitmap,
+            frames: frames,
+            free: 0,
+            next: 0,
+        };
+
+        for area in areas {
+            allocator.mark_free(area.start_frame().number, area.end_frame().number);
+        }
+        for section in kernel.sections().filter(|section| section.size > 0) {
+            let start = section.phys_start / PAGE_SIZE;
+            let end = (section.phys_start + section.size - 1) / PAGE_SIZE + 1;
+            allocator.mark_used(start, end);
+        }
+        allocator.mark_used(bitmap_start, bitmap_start + bitmap_frames);
+        // nothing good ever comes of handing out the frame at address 0.
+        allocator.mark_used(0, 1);
+
+        Some(allocator)
+    }
+
+    /// allocate_contiguous allocates count physically contiguous frames, the
+    /// first of which is a multiple of align frames. align has to be a power
+    /// of 2. it returns the first frame, or None if there isn't a run of
+    /// frames that big free.
+    pub fn allocate_contiguous(&mut self, count: usize, align: usize) -> Option<Frame> {
+        assert!(align.is_power_of_two(), "'align' must be a power of 2");
+        if count == 0 {
+            return None;
+        }
+
+        let start = self.find_free(count, align)?;
+        self.mark_used(start, start + count);
+        if count == 1 && align == 1 {
+            // a single frame search checks every frame up to the one it
+            // finds, so they are all in use now.
+            self.next = start + 1;
+        }
+        Some(Frame { number: start })
+    }
+
+    /// deallocate_contiguous frees count contiguous frames starting at start,
+    /// like a run of them from allocate_contiguous.
+    pub fn deallocate_contiguous(&mut self, start: Frame, count: usize) {
+        for number in start.number..start.number + count {
+            self.deallocate_frame(Frame { number: number });
+        }
+    }
+
+    /// free_frames returns how many frames are free.
+    pub fn free_frames(&self) -> usize {
+        self.free
+    }
+
+    /// used_frames returns how many frames the bitmap covers that are in use,
+    /// including the ones that were never usable in the first place.
+    pub fn used_frames(&self) -> usize {
+        self.frames - self.free
+    }
+
+    /// find_free returns the number of the first frame of a free run of count
+    /// frames that starts at a multiple of align.
+    fn find_free(&self, count: usize, align: usize) -> Option<usize> {
+        let mut start = align_up(self.next, align);
+        while start + count <= self.frames {
+            // skip over whole words of used frames at a time
+            if start % BITS == 0 && self.bitmap[start / BITS] == !0 {
+                start = align_up(start + BITS, align);
+                continue;
+            }
+
+            match (start..start + count).find(|&number| self.is_used(number)) {
+                Some(used) => start = align_up(used + 1, align),
+                None => return Some(start),
+            }
+        }
+        None
+    }
+
+    fn is_used(&self, number: usize) -> bool {
+        self.bitmap[number / BITS] & (1 << (number % BITS)) != 0
+    }
+
+    /// mark_free marks the frames from start up to end as free.
+    fn mark_free(&mut self, start: usize, end: usize) {
+        for number in start..end.min(self.frames) {
+            if self.is_used(number) {
+                self.bitmap[number / BITS] &= !(1 << (number % BITS));
+                self.free += 1;
+            }
+        }
+    }
+
+    /// mark_used marks the frames from start up to end as used.
+    fn mark_used(&mut self, start: usize, end: usize) {
+        for number in start..end.min(self.frames) {
+            if !self.is_used(number) {
+                self.bitmap[number / BITS] |= 1 << (number % BITS);
+                self.free -= 1;
+            }
+        }
+    }
+}
+
+impl FrameAllocator for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<Frame> {
+        self.allocate_contiguous(1, 1)
+    }
+
+    /// deallocate_frame marks the frame as free. it panics if the frame is
+    /// already free. frames past the end of the bitmap were never ours to
+    /// begin with, so they are ignored.
+    fn deallocate_frame(&mut self, frame: Frame) {
+        let number = frame.number;
+        if number >= self.frames {
+            warn!("freeing frame {:#x}, which isn't in usable memory", frame.start_address());
+            return;
+        }
+
+        assert!(self.is_used(number), "frame {:#x} freed twice", frame.start_address());
+        self.mark_free(number, number + 1);
+        if number < self.next {
+            self.next = number;
+        }
+    }
+}
+
+/// find_room returns the first frame of a run of count frames in the area
+/// that isn't in the kernel image, if there is one. the buddy allocator uses
+/// it too, to find somewhere for its own bookkeeping.
+pub fn find_room(kernel: &KernelImage, area: &MemoryRegion, count: usize) -> Option<usize> {
+    // stay off frame 0 here too, since it's going to be marked as used.
+    let mut start = area.start_frame().number.max(1);
+    while start + count <= area.end_frame().number {
+        let overlap = kernel.sections()
+            .filter(|section| section.size > 0)
+            .map(|section| (section.phys_start / PAGE_SIZE,
+                            (section.phys_start + section.size - 1) / PAGE_SIZE + 1))
+            .find(|&(section_start, section_end)|
+                  section_start < start + count && section_end > start);
+        match overlap {
+            Some((_, section_end)) => start = section_end,
+            None => return Some(start),
+        }
+    }
+    None
+}
+
+fn align_up(number: usize, align: usize) -> usize {
+    (number + align - 1) & !(align - 1)
+}
@@ -0,0 +1,457 @@
+//! buddy_frame_allocator hands out physically contiguous blocks of frames in
+//! power of 2 sizes, aligned to their size, which is what dma buffers and huge
+//! pages want. a block of order n is 2^n frames. every free block has a buddy,
+//! the other half of the block of order n + 1 it was split from, and when both
+//! halves are free again they get merged back together.
+//!
+//! the free lists are kept in the free blocks themselves, so the only other
+//! bookkeeping is a bitmap with a bit for every frame that starts a free
+//! block. like the bitmap allocator, that is carved out of usable memory.
+//! all of it is accessed through wherever physical memory is mapped, which in
+//! the kernel is at its physical address, since physical memory is identity
+//! mapped there. tests hand it a buffer to pretend is physical memory instead.
+
+use bitmap_frame_allocator::find_room;
+use core::slice;
+use kernel_image::{KernelImage, MAX_SECTIONS};
+use region::MemoryRegion;
+use super::{Frame, FrameAllocator, PAGE_SIZE};
+
+/// MAX_ORDER is the order of the biggest block we keep track of, which is
+/// 1GiB, the size of the biggest huge page.
+#[cfg(not(test))]
+pub const MAX_ORDER: usize = 18;
+/// MAX_ORDER is a lot smaller in tests, so the fake memory behind a whole
+/// max order block is only 4MiB.
+#[cfg(test)]
+pub const MAX_ORDER: usize = 10;
+
+/// MAX_AREAS is how many usable regions we look at while seeding the free
+/// lists. any past this are never handed out.
+const MAX_AREAS: usize = 64;
+
+/// BITS is how many frames one word of the bitmap keeps track of.
+const BITS: usize = 64;
+
+/// BuddyStats is a snapshot of what the allocator has been up to.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BuddyStats {
+    /// free_frames is how many frames are free.
+    pub free_frames: usize,
+    /// free_blocks is how many free blocks there are of each order.
+    pub free_blocks: [usize; MAX_ORDER + 1],
+    /// splits is how many times a block was split in two to satisfy an
+    /// allocation.
+    pub splits: usize,
+    /// merges is how many times a freed block was merged with its buddy.
+    pub merges: usize,
+}
+
+/// FreeBlock sits at the start of every free block, and links it into the
+/// free list for its order.
+struct FreeBlock {
+    order: usize,
+    prev: Option<usize>,
+    next: Option<usize>,
+}
+
+pub struct BuddyFrameAllocator {
+    /// heads has a bit for every frame, set if it's the first frame of a free
+    /// block.
+    heads: &'static mut [u64],
+    /// frames is how many frames heads covers, starting from frame 0.
+    frames: usize,
+    /// offset is the virtual address physical address 0 is mapped at.
+    offset: usize,
+    /// free_lists has the first free block of each order.
+    free_lists: [Option<usize>; MAX_ORDER + 1],
+    stats: BuddyStats,
+}
+
+impl BuddyFrameAllocator {
+    /// new makes an allocator that hands out blocks from the usable regions,
+    /// leaving out the kernel image and its own bookkeeping. it returns None
+    /// if none of the usable regions have room for the bookkeeping. it relies
+    /// on physical memory being identity mapped.
+    pub fn new<I>(kernel: &KernelImage, regions: I) -> Option<BuddyFrameAllocator>
+        where I: IntoIterator<Item = MemoryRegion>
+    {
+        unsafe { BuddyFrameAllocator::with_offset(kernel, regions, 0) }
+    }
+
+    /// with_offset is new, for when physical memory is mapped at offset instead
+    /// of at its physical address. it's unsafe because every usable region has
+    /// to actually be mapped there, and not be used for anything else.
+    pub unsafe fn with_offset<I>(kernel: &KernelImage, regions: I, offset: usize)
+                                 -> Option<BuddyFrameAllocator>
+        where I: IntoIterator<Item = MemoryRegion>
+    {
+        let mut areas = [None; MAX_AREAS];
+        let usable = regions.into_iter().filter(|region| region.is_usable());
+        for (area, region) in areas.iter_mut().zip(usable) {
+            *area = Some(region);
+        }
+        let areas = areas.iter().filter_map(|area| *area);
+
+        let frames = areas.clone()
+            .map(|area| area.end_frame().number)
+            .max()
+            .unwrap_or(0);
+        let words = (frames + BITS - 1) / BITS;
+        let heads_frames = (words * 8 + PAGE_SIZE - 1) / PAGE_SIZE;
+
+        let heads_start = areas.clone()
+            .filter_map(|area| find_room(kernel, &area, heads_frames))
+            .next()?;
+        let heads = slice::from_raw_parts_mut((offset + heads_start * PAGE_SIZE) as *mut u64,
+                                              words);
+        for word in heads.iter_mut() {
+            *word = 0;
+        }
+
+        // everything in the usable regions that we can't hand out. frame 0
+        // is in here because nothing good ever comes of handing it out.
+        let mut reserved = [(0, 0); MAX_SECTIONS + 2];
+        reserved[0] = (0, 1);
+        reserved[1] = (heads_start, heads_start + heads_frames);
+        for (range, section) in reserved[2..].iter_mut().zip(kernel.sections()) {
+            if section.size > 0 {
+                *range = (section.phys_start / PAGE_SIZE,
+                          (section.phys_start + section.size - 1) / PAGE_SIZE + 1);
+            }
+        }
+
+        let mut allocator = BuddyFrameAllocator {
+            heads: heads,
+            frames: frames,
+            offset: offset,
+            free_lists: [None; MAX_ORDER + 1],
+            stats: BuddyStats::default(),
+        };
+
+        for area in areas {
+            let end = area.end_frame().number;
+            let mut start = area.start_frame().number;
+            while start < end {
+                if let Some(&(_, reserved_end)) = reserved.iter()
+                    .find(|&&(s, e)| s <= start && start < e)
+                {
+                    start = reserved_end;
+                    continue;
+                }
+
+                let next = reserved.iter()
+                    .map(|&(s, _)| s)
+                    .filter(|&s| s > start)
+                    .fold(end, |next, s| next.min(s));
+                allocator.free_range(start, next);
+                start = next;
+            }
+        }
+
+        // seeding merges blocks, but that isn't anything anyone did.
+        allocator.stats.splits = 0;
+        allocator.stats.merges = 0;
+
+        Some(allocator)
+    }
+
+    /// allocate_order allocates a block of 2^order contiguous frames, aligned
+    /// to its size. it returns the first frame of the block, or None if there
+    /// isn't a free block that big.
+    pub fn allocate_order(&mut self, order: usize) -> Option<Frame> {
+        assert!(order <= MAX_ORDER, "order {} is bigger than the max of {}", order, MAX_ORDER);
+
+        let from = (order..MAX_ORDER + 1).find(|&o| self.free_lists[o].is_some())?;
+        let number = self.free_lists[from].unwrap();
+        self.remove(number, from);
+
+        // split the block in half until it's the size we want, putting the
+        // upper halves back on the free lists.
+        for o in (order..from).rev() {
+            self.push(number + (1 << o), o);
+            self.stats.splits += 1;
+        }
+
+        self.stats.free_frames -= 1 << order;
+        Some(Frame { number: number })
+    }
+
+    /// free_order frees a block of 2^order frames that came from
+    /// allocate_order with the same order, merging it with its buddy for as
+    /// long as the buddy is free too.
+    pub fn free_order(&mut self, frame: Frame, order: usize) {
+        assert!(order <= MAX_ORDER, "order {} is bigger than the max of {}", order, MAX_ORDER);
+        let mut number = frame.number;
+        assert!(number % (1 << order) == 0,
+                "frame {:#x} isn't the start of an order {} block", frame.start_address(), order);
+        assert!(number + (1 << order) <= self.frames,
+                "frame {:#x} isn't in usable memory", frame.start_address());
+        assert!(!self.is_head(number), "frame {:#x} freed twice", frame.start_address());
+
+        self.stats.free_frames += 1 << order;
+
+        let mut order = order;
+        while order < MAX_ORDER {
+            let buddy = number ^ (1 << order);
+            if buddy + (1 << order) > self.frames || !self.is_head(buddy) ||
+                unsafe { self.block(buddy) }.order != order
+            {
+                break;
+            }
+
+            self.remove(buddy, order);
+            number = number.min(buddy);
+            order += 1;
+            self.stats.merges += 1;
+        }
+
+        self.push(number, order);
+    }
+
+    /// stats returns a snapshot of the allocator's statistics.
+    pub fn stats(&self) -> BuddyStats {
+        self.stats
+    }
+
+    /// free_range frees the frames from start up to end, as the biggest
+    /// blocks they can be split into.
+    fn free_range(&mut self, mut start: usize, end: usize) {
+        while start < end {
+            let order = (0..MAX_ORDER + 1).rev()
+                .find(|&o| start % (1 << o) == 0 && start + (1 << o) <= end)
+                .unwrap();
+            self.free_order(Frame { number: start }, order);
+            start += 1 << order;
+        }
+    }
+
+    fn is_head(&self, number: usize) -> bool {
+        self.heads[number / BITS] & (1 << (number % BITS)) != 0
+    }
+
+    /// block returns the header of the free block starting at the given frame.
+    /// it's only valid to use while the block is free.
+    unsafe fn block(&self, number: usize) -> &'static mut FreeBlock {
+        &mut *((self.offset + number * PAGE_SIZE) as *mut FreeBlock)
+    }
+
+    /// push puts the block starting at number on the free list for order.
+    fn push(&mut self, number: usize, order: usize) {
+        let next = self.free_lists[order];
+        unsafe {
+            *self.block(number) = FreeBlock { order: order, prev: None, next: next };
+            if let Some(next) = next {
+                self.block(next).prev = Some(number);
+            }
+        }
+        self.free_lists[order] = Some(number);
+        self.heads[number / BITS] |= 1 << (number % BITS);
+        self.stats.free_blocks[order] += 1;
+    }
+
+    /// remove takes the block starting at number off the free list for order.
+    fn remove(&mut self, number: usize, order: usize) {
+        let (prev, next) = unsafe {
+            let block = self.block(number);
+            (block.prev, block.next)
+        };
+        unsafe {
+            match prev {
+                Some(prev) => self.block(prev).next = next,
+                None => self.free_lists[order] = next,
+            }
+            if let Some(next) = next {
+                self.block(next).prev = prev;
+            }
+        }
+        self.heads[number / BITS] &= !(1 << (number % BITS));
+        self.stats.free_blocks[order] -= 1;
+    }
+}
+
+impl FrameAllocator for BuddyFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<Frame> {
+        self.allocate_order(0)
+    }
+
+    fn deallocate_frame(&mut self, frame: Frame) {
+        self.free_order(frame, 0)
+    }
+}
+
+/// order_for returns the order of the smallest block that holds the given
+/// number of frames.
+pub fn order_for(frames: usize) -> usize {
+    frames.next_power_of_two().trailing_zeros() as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use kernel_image::{KernelImage, Section};
+    use paging::EntryFlags;
+    use region::{MemoryRegion, MemoryRegionKind};
+    use std::vec::Vec;
+    use super::*;
+
+    /// FakeMemory is a buffer that stands in for physical memory from start up
+    /// to end.
+    struct FakeMemory {
+        memory: Vec<u64>,
+        start: usize,
+    }
+
+    impl FakeMemory {
+        fn new(start: usize, end: usize) -> FakeMemory {
+            FakeMemory {
+                memory: vec![0; (end - start) / 8],
+                start: start,
+            }
+        }
+
+        /// offset is where physical address 0 would be if the buffer was
+        /// physical memory.
+        fn offset(&self) -> usize {
+            self.memory.as_ptr() as usize - self.start
+        }
+    }
+
+    fn usable(start: usize, end: usize) -> MemoryRegion {
+        MemoryRegion {
+            start: start,
+            end: end,
+            kind: MemoryRegionKind::Usable,
+        }
+    }
+
+    /// allocator makes an allocator over the regions, with fake memory behind
+    /// them. the memory has to stick around as long as the allocator does.
+    fn allocator(kernel: &KernelImage, regions: &[MemoryRegion])
+                 -> (FakeMemory, BuddyFrameAllocator)
+    {
+        let start = regions.iter().map(|region| region.start).min().unwrap();
+        let end = regions.iter().map(|region| region.end).max().unwrap();
+        let memory = FakeMemory::new(start, end);
+        let allocator = unsafe {
+            BuddyFrameAllocator::with_offset(kernel, regions.iter().cloned(), memory.offset())
+        }.unwrap();
+        (memory, allocator)
+    }
+
+    /// small is a fake memory map with two usable regions. the first is frames
+    /// 0 and 1, which are frame 0, which is never handed out, and room for the
+    /// heads bitmap. the second is a single free order 9 block, at 2MiB.
+    fn small() -> (FakeMemory, BuddyFrameAllocator) {
+        allocator(&KernelImage::new(), &[usable(0, 2 * PAGE_SIZE),
+                                         usable(0x200000, 0x400000)])
+    }
+
+    #[test]
+    fn split_and_merge_counts() {
+        let (_memory, mut allocator) = small();
+        assert_eq!(allocator.stats().free_frames, 512);
+        assert_eq!(allocator.stats().free_blocks[9], 1);
+
+        let frame = allocator.allocate_order(0).unwrap();
+        assert_eq!(frame.number, 512);
+        let stats = allocator.stats();
+        assert_eq!(stats.splits, 9);
+        assert_eq!(stats.free_frames, 511);
+        // one of each order below 9 is left over from splitting.
+        assert!(stats.free_blocks[..9].iter().all(|&blocks| blocks == 1));
+        assert_eq!(stats.free_blocks[9], 0);
+
+        allocator.free_order(frame, 0);
+        let stats = allocator.stats();
+        assert_eq!(stats.merges, 9);
+        assert_eq!(stats.free_frames, 512);
+        assert_eq!(stats.free_blocks[9], 1);
+        assert!(stats.free_blocks[..9].iter().all(|&blocks| blocks == 0));
+    }
+
+    #[test]
+    fn allocate_order_is_aligned() {
+        let (_memory, mut allocator) = small();
+        let orders = [0, 3, 1, 5, 2, 0, 4];
+        let mut frames = Vec::new();
+        for &order in orders.iter() {
+            let frame = allocator.allocate_order(order).unwrap();
+            assert_eq!(frame.number % (1 << order), 0,
+                       "order {} block at frame {}", order, frame.number);
+            assert!(frame.number >= 512 && frame.number + (1 << order) <= 1024);
+            frames.push((frame, order));
+        }
+
+        for (frame, order) in frames {
+            allocator.free_order(frame, order);
+        }
+        assert_eq!(allocator.stats().free_blocks[9], 1);
+        assert_eq!(allocator.stats().free_frames, 512);
+    }
+
+    #[test]
+    fn merges_back_to_max_order() {
+        // a whole max order block, plus room below it for the heads bitmap.
+        let block = (1 << MAX_ORDER) * PAGE_SIZE;
+        let (_memory, mut allocator) = allocator(&KernelImage::new(),
+                                                 &[usable(0, 2 * PAGE_SIZE),
+                                                   usable(block, 2 * block)]);
+        assert_eq!(allocator.stats().free_blocks[MAX_ORDER], 1);
+
+        let mut frames = Vec::new();
+        for order in 0..6 {
+            frames.push((allocator.allocate_order(order).unwrap(), order));
+            frames.push((allocator.allocate_order(order).unwrap(), order));
+        }
+        assert_eq!(allocator.stats().free_blocks[MAX_ORDER], 0);
+
+        // free them in a different order than they were allocated in.
+        frames.reverse();
+        frames.swap(0, 5);
+        for (frame, order) in frames {
+            allocator.free_order(frame, order);
+        }
+        let stats = allocator.stats();
+        assert_eq!(stats.free_blocks[MAX_ORDER], 1);
+        assert_eq!(stats.free_frames, 1 << MAX_ORDER);
+        assert!(stats.free_blocks[..MAX_ORDER].iter().all(|&blocks| blocks == 0));
+
+        let frame = allocator.allocate_order(MAX_ORDER).unwrap();
+        assert_eq!(frame.start_address(), block);
+        assert!(allocator.allocate_order(0).is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "freed twice")]
+    fn double_free() {
+        let (_memory, mut allocator) = small();
+        let frame = allocator.allocate_order(0).unwrap();
+        let number = frame.number;
+        allocator.free_order(frame, 0);
+        allocator.free_order(Frame { number: number }, 0);
+    }
+
+    #[test]
+    fn reserved_frames_are_never_handed_out() {
+        let mut kernel = KernelImage::new();
+        kernel.push(Section {
+            virt_start: 0xffff_ff00_0000_0000,
+            phys_start: 0x280000,
+            size: 0x3000,
+            flags: EntryFlags::PRESENT,
+        });
+        let (_memory, mut allocator) = allocator(&kernel, &[usable(0, 2 * PAGE_SIZE),
+                                                            usable(0x200000, 0x400000)]);
+        assert_eq!(allocator.stats().free_frames, 509);
+
+        let mut allocated = 0;
+        while let Some(frame) = allocator.allocate_order(0) {
+            assert!(frame.number != 0, "frame 0 was handed out");
+            assert!(frame.number != 1, "the heads bitmap was handed out");
+            assert!(frame.number < 0x280 || frame.number >= 0x283,
+                    "the kernel's frame {:#x} was handed out", frame.number);
+            allocated += 1;
+        }
+        assert_eq!(allocated, 509);
+    }
+}
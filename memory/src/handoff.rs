@@ -0,0 +1,53 @@
+//! handoff adapts the boot information our uefi bootloader hands the kernel
+//! into the boot protocol neutral types the rest of the crate uses.
+
+use bootinfo::{self, BootInfo};
+use kernel_image::{KernelImage, Section};
+use paging::EntryFlags;
+use region::{MemoryRegion, MemoryRegionKind};
+
+/// memory_regions returns the bootloader's memory map as MemoryRegions.
+pub fn memory_regions<'a>(boot_info: &'a BootInfo) -> impl Iterator<Item = MemoryRegion> + Clone + 'a {
+    boot_info.memory_map.regions().iter().map(|region| MemoryRegion {
+        start: region.start as usize,
+        end: region.end as usize,
+        kind: region_kind(region.kind),
+    })
+}
+
+/// kernel_image describes the kernel from the segments the bootloader loaded.
+pub fn kernel_image(boot_info: &BootInfo) -> KernelImage {
+    let mut image = KernelImage::new();
+
+    for segment in boot_info.kernel_segments.segments() {
+        let mut flags = EntryFlags::empty();
+        if segment.flags & bootinfo::SEGMENT_WRITABLE != 0 {
+            flags = flags | EntryFlags::WRITABLE;
+        }
+        if segment.flags & bootinfo::SEGMENT_EXECUTABLE == 0 {
+            flags = flags | EntryFlags::NO_EXECUTE;
+        }
+
+        image.push(Section {
+            virt_start: segment.virt_start as usize,
+            phys_start: segment.phys_start as usize,
+            size: segment.size as usize,
+            flags,
+        });
+    }
+
+    image
+}
+
+/// region_kind maps the bootloader's region kinds onto ours. we only care
+/// about whether we can use a region now, later, or never.
+fn region_kind(kind: bootinfo::MemoryRegionKind) -> MemoryRegionKind {
+    use bootinfo::MemoryRegionKind::*;
+
+    match kind {
+        Usable => MemoryRegionKind::Usable,
+        Bootloader => MemoryRegionKind::Bootloader,
+        BootServices | AcpiReclaimable => MemoryRegionKind::Reclaimable,
+        RuntimeServices | AcpiNvs | Mmio | Unusable | Reserved => MemoryRegionKind::Reserved,
+    }
+}
@@ -1,55 +1,322 @@
-/// heap allocator is a simple allocator that allocates memory linearly and
-/// ignores freed memory. another name for it is a bump allocator. it's not very
-/// good.
+/// heap allocator is the kernel heap. it keeps a list of the free blocks of
+/// memory in the heap, sorted by address, in the free blocks themselves.
+/// allocating takes the first block that fits, and freeing puts the block
+/// back, merging it with its neighbors if they are free too, so the heap
+/// doesn't fall apart into little pieces over time.
+///
+/// the heap starts out as however much memory::init maps for it. when nothing
+/// in the heap fits, it maps more pages onto the end, up to the most it was
+/// told it could have.
+///
+/// small allocations don't go to the heap at all. they go to slab caches for
+/// a handful of power of 2 size classes, which are quicker and don't leave
+/// little holes all over the heap.
 
-use alloc::alloc::{Alloc, AllocErr, Layout};
+use core::alloc::{GlobalAlloc, Layout};
+use core::{cmp, mem, ptr};
 use core::ptr::NonNull;
-use core::sync::atomic::{AtomicUsize, Ordering};
+use slab_allocator::{SlabCache, SlabStats, MAX_OBJECT_SIZE};
+use spin::Mutex;
+use PAGE_SIZE;
 
-#[derive(Debug)]
-pub struct BumpAllocator {
-    heap_start: usize,
-    heap_end: usize,
-    next: AtomicUsize,
+/// GROW_SIZE is the least we grow the heap by at once, so a bunch of small
+/// allocations don't each have to map a page.
+const GROW_SIZE: usize = 16 * PAGE_SIZE;
+
+/// SIZE_CLASSES is how many slab caches small allocations are split between.
+/// they go from MIN_SIZE_CLASS up to MAX_OBJECT_SIZE, doubling each time.
+pub const SIZE_CLASSES: usize = 6;
+
+/// MIN_SIZE_CLASS is the size of the smallest size class.
+const MIN_SIZE_CLASS: usize = 16;
+
+/// EMPTY_SLABS_KEPT is how many empty slabs each size class hangs on to, so
+/// allocating and freeing the same thing over and over doesn't keep going
+/// back to the frame allocator.
+const EMPTY_SLABS_KEPT: usize = 1;
+
+/// FreeBlock sits at the start of every free block in the heap.
+struct FreeBlock {
+    size: usize,
+    next: *mut FreeBlock,
+}
+
+/// MIN_BLOCK is the smallest a block in the heap can be, since a free one
+/// has to have room for its FreeBlock.
+const MIN_BLOCK: usize = mem::size_of::<FreeBlock>();
+
+/// HeapStats is a snapshot of how much of the heap is being used.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HeapStats {
+    /// size is how big the heap is, which is how much of it is mapped.
+    pub size: usize,
+    /// used is how many bytes are allocated, counting what allocations were
+    /// rounded up by.
+    pub used: usize,
+    /// free is how many bytes are free.
+    pub free: usize,
+    /// allocations is how many allocations there are right now.
+    pub allocations: usize,
+    /// grows is how many times the heap has grown.
+    pub grows: usize,
+}
+
+pub struct Heap {
+    /// free is the first free block, in address order.
+    free: *mut FreeBlock,
+    /// top is the end of the mapped part of the heap.
+    top: usize,
+    /// limit is as far as the heap is allowed to grow.
+    limit: usize,
+    stats: HeapStats,
 }
 
-impl BumpAllocator {
-    pub const fn new(heap_start: usize, heap_end: usize) -> Self {
-        Self {
-            heap_start: heap_start,
-            heap_end: heap_end,
-            next: AtomicUsize::new(heap_start),
+// the heap only ever points into memory it owns.
+unsafe impl Send for Heap {}
+
+impl Heap {
+    /// empty returns a heap with nothing in it, which can't allocate anything
+    /// until it's initialized.
+    pub const fn empty() -> Self {
+        Heap {
+            free: ptr::null_mut(),
+            top: 0,
+            limit: 0,
+            stats: HeapStats {
+                size: 0,
+                used: 0,
+                free: 0,
+                allocations: 0,
+                grows: 0,
+            },
         }
     }
-}
 
-unsafe impl<'a> Alloc for &'a BumpAllocator {
-    unsafe fn alloc(&mut self, layout: Layout) -> Result<NonNull<u8>, AllocErr> {
-        loop {
-            // load current state of the next field
-            let current_next = self.next.load(Ordering::Relaxed);
-            let alloc_start = align_up(current_next, layout.align());
-            let alloc_end = alloc_start.saturating_add(layout.size());
-
-            if alloc_end <= self.heap_end {
-                // update the `next` pointer if it still has the value
-                // `current_next`
-                let next_now = self.next
-                    .compare_and_swap(current_next, alloc_end, Ordering::Relaxed);
-                if next_now == current_next {
-                    // next address was successfully updated allocation
-                    // succeeded.
-                    return Ok(NonNull::new_unchecked(alloc_start as *mut u8));
+    /// init gives the heap the size bytes at start, which have to be mapped
+    /// already, and lets it grow up to max_size bytes.
+    pub unsafe fn init(&mut self, start: usize, size: usize, max_size: usize) {
+        assert!(self.top == 0, "the heap is already initialized");
+        assert!(size <= max_size);
+
+        self.top = start;
+        self.limit = start + max_size;
+        self.extend(size);
+        self.stats.grows = 0;
+    }
+
+    /// allocate returns a block of memory that fits the layout, out of what
+    /// the heap already has, or None if nothing fits.
+    pub fn allocate(&mut self, layout: Layout) -> Option<NonNull<u8>> {
+        let (size, align) = block_layout(layout);
+
+        unsafe {
+            let mut prev: *mut *mut FreeBlock = &mut self.free;
+            while !(*prev).is_null() {
+                let block = *prev;
+                let block_start = block as usize;
+                let block_end = block_start + (*block).size;
+
+                // whatever is left in front of the allocation has to be big
+                // enough to stay on the free list.
+                let mut start = align_up(block_start, align);
+                if start != block_start && start - block_start < MIN_BLOCK {
+                    start = align_up(block_start + MIN_BLOCK, align);
+                }
+                let end = start.saturating_add(size);
+
+                // and so does whatever is left behind it.
+                if end <= block_end && (end == block_end || block_end - end >= MIN_BLOCK) {
+                    *prev = (*block).next;
+                    self.stats.free -= block_end - block_start;
+                    if start != block_start {
+                        self.insert(block_start, start - block_start);
+                    }
+                    if end != block_end {
+                        self.insert(end, block_end - end);
+                    }
+
+                    self.stats.used += size;
+                    self.stats.allocations += 1;
+                    return NonNull::new(start as *mut u8);
                 }
-            } else {
-                return Err(AllocErr);
+
+                prev = &mut (*block).next;
             }
         }
+
+        None
     }
 
-    unsafe fn dealloc(&mut self, _ptr: NonNull<u8>, _layout: Layout) {
-        // do nothing, leak memory
+    /// deallocate gives back a block from allocate. layout has to be the same
+    /// one it was allocated with.
+    pub unsafe fn deallocate(&mut self, ptr: NonNull<u8>, layout: Layout) {
+        let (size, _) = block_layout(layout);
+        self.insert(ptr.as_ptr() as usize, size);
+        self.stats.used -= size;
+        self.stats.allocations -= 1;
     }
+
+    /// grow maps enough more memory onto the end of the heap to fit the
+    /// layout. it returns whether it managed to.
+    pub fn grow(&mut self, layout: Layout) -> bool {
+        let (size, align) = block_layout(layout);
+        let wanted = align_up(size + align + MIN_BLOCK, PAGE_SIZE);
+        let size = cmp::min(cmp::max(wanted, GROW_SIZE), self.limit - self.top);
+        if size < wanted || !super::map_heap(self.top, size) {
+            return false;
+        }
+
+        unsafe { self.extend(size) };
+        true
+    }
+
+    /// stats returns a snapshot of the heap's statistics.
+    pub fn stats(&self) -> HeapStats {
+        self.stats
+    }
+
+    /// extend adds the size bytes past the top of the heap, which have to be
+    /// mapped already, to the heap.
+    unsafe fn extend(&mut self, size: usize) {
+        let top = self.top;
+        self.insert(top, size);
+        self.top += size;
+        self.stats.size += size;
+        self.stats.grows += 1;
+    }
+
+    /// insert puts a block on the free list, merging it with the blocks on
+    /// either side of it if they are right next to it.
+    unsafe fn insert(&mut self, addr: usize, size: usize) {
+        let mut prev: *mut FreeBlock = ptr::null_mut();
+        let mut next = self.free;
+        while !next.is_null() && (next as usize) < addr {
+            prev = next;
+            next = (*next).next;
+        }
+
+        let block = addr as *mut FreeBlock;
+        ptr::write(block, FreeBlock { size: size, next: next });
+        self.stats.free += size;
+
+        if !next.is_null() && addr + size == next as usize {
+            (*block).size += (*next).size;
+            (*block).next = (*next).next;
+        }
+
+        if prev.is_null() {
+            self.free = block;
+        } else if prev as usize + (*prev).size == addr {
+            (*prev).size += (*block).size;
+            (*prev).next = (*block).next;
+        } else {
+            (*prev).next = block;
+        }
+    }
+}
+
+/// LockedHeap is the heap and the small allocation slab caches behind locks,
+/// so they can be the global allocator. the global allocator has to be
+/// defined in the kernel itself, so this is what it defines it as.
+pub struct LockedHeap {
+    heap: Mutex<Heap>,
+    slabs: Mutex<[SlabCache; SIZE_CLASSES]>,
+}
+
+impl LockedHeap {
+    /// empty returns a heap that can't allocate anything until it's
+    /// initialized.
+    pub const fn empty() -> Self {
+        LockedHeap {
+            heap: Mutex::new(Heap::empty()),
+            slabs: Mutex::new([
+                SlabCache::with_layout("slab-16", 16, 16),
+                SlabCache::with_layout("slab-32", 32, 32),
+                SlabCache::with_layout("slab-64", 64, 64),
+                SlabCache::with_layout("slab-128", 128, 128),
+                SlabCache::with_layout("slab-256", 256, 256),
+                SlabCache::with_layout("slab-512", 512, 512),
+            ]),
+        }
+    }
+
+    /// init gives the heap the size bytes at start, which have to be mapped
+    /// already, and lets it grow up to max_size bytes.
+    pub unsafe fn init(&self, start: usize, size: usize, max_size: usize) {
+        self.heap.lock().init(start, size, max_size)
+    }
+
+    /// stats returns a snapshot of the heap's statistics.
+    pub fn stats(&self) -> HeapStats {
+        self.heap.lock().stats()
+    }
+
+    /// slab_stats returns a snapshot of the statistics of each of the small
+    /// allocation size classes.
+    pub fn slab_stats(&self) -> [SlabStats; SIZE_CLASSES] {
+        let slabs = self.slabs.lock();
+        let mut stats = [SlabStats::default(); SIZE_CLASSES];
+        for (stats, cache) in stats.iter_mut().zip(slabs.iter()) {
+            *stats = cache.stats();
+        }
+        stats
+    }
+}
+
+unsafe impl GlobalAlloc for LockedHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if let Some(class) = size_class(layout) {
+            let mut slabs = self.slabs.lock();
+            let cache = &mut slabs[class];
+            // only go to the memory controller, and take its lock, if every
+            // slab is full and we need a new one.
+            if let Some(object) = cache.try_alloc() {
+                return object.as_ptr();
+            }
+            return super::with_frames(|frames| cache.alloc(frames))
+                .and_then(|object| object)
+                .map_or(ptr::null_mut(), |object| object.as_ptr());
+        }
+
+        let mut heap = self.heap.lock();
+        heap.allocate(layout)
+            .or_else(|| if heap.grow(layout) { heap.allocate(layout) } else { None })
+            .map_or(ptr::null_mut(), |ptr| ptr.as_ptr())
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if let Some(class) = size_class(layout) {
+            let mut slabs = self.slabs.lock();
+            let cache = &mut slabs[class];
+            cache.free(NonNull::new_unchecked(ptr));
+            if cache.empty_slabs() > EMPTY_SLABS_KEPT {
+                super::with_frames(|frames| cache.reclaim(EMPTY_SLABS_KEPT, frames));
+            }
+            return;
+        }
+
+        self.heap.lock().deallocate(NonNull::new_unchecked(ptr), layout)
+    }
+}
+
+/// size_class returns which slab cache allocations with the layout go to, if
+/// they are small enough to go to one.
+fn size_class(layout: Layout) -> Option<usize> {
+    let size = cmp::max(cmp::max(layout.size(), layout.align()), MIN_SIZE_CLASS);
+    if size > MAX_OBJECT_SIZE {
+        return None;
+    }
+
+    let class = size.next_power_of_two().trailing_zeros() - MIN_SIZE_CLASS.trailing_zeros();
+    Some(class as usize)
+}
+
+/// block_layout returns the size and alignment of the block we hand out for
+/// the layout. every block has to be able to hold a FreeBlock once it's freed.
+fn block_layout(layout: Layout) -> (usize, usize) {
+    let align = cmp::max(layout.align(), mem::align_of::<FreeBlock>());
+    let size = align_up(cmp::max(layout.size(), MIN_BLOCK), mem::align_of::<FreeBlock>());
+    (size, align)
 }
 
 /// align_down returns the greatest x with alignment `align` so that x <= addr.
@@ -0,0 +1,66 @@
+//! kernel_image describes where the kernel is in memory, in a way that doesn't
+//! care how we were booted. it's what paging::init uses to map the kernel into
+//! the page tables it builds.
+
+use paging::{EntryFlags, PhysicalAddress, VirtualAddress};
+
+/// MAX_SECTIONS is how many sections a KernelImage can hold. the kernel has a
+/// handful of segments, and we don't have a heap yet when we need this, so it
+/// lives in a fixed size array.
+pub const MAX_SECTIONS: usize = 16;
+
+/// Section is a contiguous piece of the kernel, with the same permissions all
+/// the way through.
+#[derive(Debug, Clone, Copy)]
+pub struct Section {
+    /// virt_start is the virtual address the section is mapped at.
+    pub virt_start: VirtualAddress,
+    /// phys_start is the physical address the section is loaded at. it has
+    /// the same offset into its frame as virt_start has into its page.
+    pub phys_start: PhysicalAddress,
+    /// size is the size of the section in bytes.
+    pub size: usize,
+    /// flags are the flags the section gets mapped with.
+    pub flags: EntryFlags,
+}
+
+/// KernelImage is the list of sections that make up the kernel.
+#[derive(Debug, Clone, Copy)]
+pub struct KernelImage {
+    sections: [Option<Section>; MAX_SECTIONS],
+    len: usize,
+}
+
+impl KernelImage {
+    /// new returns a kernel image with no sections in it.
+    pub fn new() -> Self {
+        KernelImage {
+            sections: [None; MAX_SECTIONS],
+            len: 0,
+        }
+    }
+
+    /// push adds a section to the kernel image. it panics if there are
+    /// already MAX_SECTIONS of them.
+    pub fn push(&mut self, section: Section) {
+        assert!(self.len < MAX_SECTIONS, "kernel has more than {} sections", MAX_SECTIONS);
+        self.sections[self.len] = Some(section);
+        self.len += 1;
+    }
+
+    /// sections returns an iterator over the sections of the kernel.
+    pub fn sections<'a>(&'a self) -> impl Iterator<Item = &'a Section> {
+        self.sections[..self.len].iter().filter_map(|section| section.as_ref())
+    }
+
+    /// phys_start returns the lowest physical address the kernel occupies.
+    pub fn phys_start(&self) -> PhysicalAddress {
+        self.sections().map(|s| s.phys_start).min().unwrap_or(0)
+    }
+
+    /// phys_end returns the physical address one past the end of the highest
+    /// section of the kernel.
+    pub fn phys_end(&self) -> PhysicalAddress {
+        self.sections().map(|s| s.phys_start + s.size).max().unwrap_or(0)
+    }
+}
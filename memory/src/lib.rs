@@ -11,24 +11,39 @@
 extern crate alloc;
 #[macro_use]
 extern crate bitflags;
+extern crate bootinfo;
 #[macro_use]
 extern crate log;
 extern crate multiboot2;
 #[macro_use]
 extern crate once;
+extern crate spin;
+#[cfg(test)]
+#[macro_use]
+extern crate std;
 extern crate x86_64;
 
-mod area_frame_allocator;
+mod bitmap_frame_allocator;
+mod buddy_frame_allocator;
+pub mod handoff;
 pub mod heap_allocator;
+mod kernel_image;
 pub mod map;
-mod paging;
+pub mod multiboot;
+pub mod paging;
+mod region;
+pub mod slab_allocator;
 mod stack_allocator;
 
-pub use self::area_frame_allocator::*;
+pub use self::bitmap_frame_allocator::BitmapFrameAllocator;
+pub use self::buddy_frame_allocator::{order_for, BuddyFrameAllocator, BuddyStats, MAX_ORDER};
+pub use self::kernel_image::{KernelImage, Section, MAX_SECTIONS};
+pub use self::region::{MemoryRegion, MemoryRegionKind};
 pub use self::stack_allocator::Stack;
 
-use multiboot2::BootInformation;
-use self::paging::{PhysicalAddress, Page};
+use self::heap_allocator::LockedHeap;
+use self::paging::{PhysicalAddress, Page, VirtualAddress};
+use spin::{Mutex, Once};
 
 pub const PAGE_SIZE: usize = 4096;
 
@@ -37,69 +52,95 @@ pub trait FrameAllocator {
     fn deallocate_frame(&mut self, frame: Frame);
 }
 
-pub fn init(boot_info: &BootInformation) -> MemoryController {
+/// MEMORY is the memory controller, once init has made it. it's global so the
+/// heap can get at it to grow. anything holding the lock must not allocate
+/// from the heap, or it deadlocks if the heap has to grow or a slab cache
+/// needs a new slab.
+static MEMORY: Once<Mutex<MemoryController>> = Once::new();
+
+/// init sets up memory management for the kernel. regions is the memory map,
+/// and kernel is where the kernel is, both in whatever shape the handoff or
+/// multiboot modules turned the boot information into. it makes a frame
+/// allocator out of the usable regions, builds the kernel's page tables,
+/// and maps the start of the kernel heap and hands it to heap, which should
+/// be the global allocator. it panics if there isn't enough usable memory
+/// for the frame allocator to keep track of it.
+pub fn init<I>(regions: I, kernel: &KernelImage, heap: &LockedHeap)
+               -> &'static Mutex<MemoryController>
+    where I: IntoIterator<Item = MemoryRegion>
+{
     assert_has_not_been_called!("memory::init must only be called once");
 
-    let memory_map_tag = boot_info.memory_map_tag()
-        .expect("memory map tag required");
-    // get some info about the kernel elf sections
-    let elf_sections_tag = boot_info.elf_sections_tag()
-        .expect("elf-sections tag required");
-
-    // get the size of the kernel
-    let kernel_start = elf_sections_tag.sections()
-        .filter(|s| s.is_allocated())
-        .map(|s| s.addr)
-        .min()
-        .unwrap();
-    let kernel_end = elf_sections_tag.sections()
-        .filter(|s| s.is_allocated())
-        .map(|s| s.addr + s.size)
-        .max()
-        .unwrap();
-    info!("kernel_start: {:#08x}, kernel_end: {:#08x}",
-             kernel_start, kernel_end);
-
-    // get the size of the multiboot area
-    info!("multiboot_start: {:#08x}, multiboot_end: {:#08x}",
-             boot_info.start_address(),
-             boot_info.end_address());
-
-    let mut frame_allocator = AreaFrameAllocator::new(
-        kernel_start as usize, kernel_end as usize,
-        boot_info.start_address(), boot_info.end_address(),
-        memory_map_tag.memory_areas()
-    );
-
-    let mut active_table = paging::init(&mut frame_allocator, boot_info);
+    for section in kernel.sections() {
+        info!("kernel section: virt {:#x}, phys {:#x}, size {:#x}",
+              section.virt_start, section.phys_start, section.size);
+    }
+
+    let mut frame_allocator = BitmapFrameAllocator::new(kernel, regions)
+        .expect("no usable region has room for the frame bitmap");
+
+    let mut active_table = paging::init(&mut frame_allocator, kernel);
 
     let heap_start_page = Page::containing_address(map::KERNEL_HEAP_OFFSET);
     let heap_end_page = Page::containing_address(map::KERNEL_HEAP_OFFSET + map::KERNEL_HEAP_SIZE-1);
 
-    for page in Page::range_inclusive(heap_start_page, heap_end_page) {
-        active_table.map(page,
-                         paging::EntryFlags::WRITABLE,
-                         &mut frame_allocator);
+    // nothing should ever run code out of the heap.
+    active_table.map_range(Page::range_inclusive(heap_start_page, heap_end_page),
+                           paging::EntryFlags::WRITABLE | paging::EntryFlags::NO_EXECUTE,
+                           &mut frame_allocator)
+        .expect("not enough memory for the kernel heap");
+
+    unsafe {
+        heap.init(map::KERNEL_HEAP_OFFSET, map::KERNEL_HEAP_SIZE, map::KERNEL_HEAP_MAX_SIZE);
     }
 
     let stack_allocator = {
-        let stack_alloc_start = heap_end_page + 1;
+        let stack_alloc_start =
+            Page::containing_address(map::KERNEL_HEAP_OFFSET + map::KERNEL_HEAP_MAX_SIZE);
         let stack_alloc_end = stack_alloc_start + 100;
         let stack_alloc_range =
             Page::range_inclusive(stack_alloc_start, stack_alloc_end);
         stack_allocator::StackAllocator::new(stack_alloc_range)
     };
 
-    MemoryController {
+    MEMORY.call_once(|| Mutex::new(MemoryController {
         active_table: active_table,
         frame_allocator: frame_allocator,
         stack_allocator: stack_allocator,
-    }
+    }))
+}
+
+/// with_frames calls f with the frame allocator, or returns None if init
+/// hasn't made it yet.
+fn with_frames<F, T>(f: F) -> Option<T>
+    where F: FnOnce(&mut BitmapFrameAllocator) -> T
+{
+    MEMORY.try().map(|memory| f(&mut memory.lock().frame_allocator))
+}
+
+/// map_heap maps size bytes of fresh memory at start, for the heap to grow
+/// into. it returns whether there were enough frames to do it.
+fn map_heap(start: VirtualAddress, size: usize) -> bool {
+    let memory = match MEMORY.try() {
+        Some(memory) => memory,
+        None => return false,
+    };
+    let mut memory = memory.lock();
+    let MemoryController {
+        ref mut active_table,
+        ref mut frame_allocator,
+        ..
+    } = *memory;
+
+    let pages = Page::range_inclusive(Page::containing_address(start),
+                                      Page::containing_address(start + size - 1));
+    let flags = paging::EntryFlags::WRITABLE | paging::EntryFlags::NO_EXECUTE;
+    active_table.map_range(pages, flags, frame_allocator).is_ok()
 }
 
 pub struct MemoryController {
     active_table: paging::ActivePageTable,
-    frame_allocator: AreaFrameAllocator,
+    frame_allocator: BitmapFrameAllocator,
     stack_allocator: stack_allocator::StackAllocator,
 }
 
@@ -116,6 +157,22 @@ impl MemoryController {
             size_in_pages,
         )
     }
+
+    /// frame_allocator returns the frame allocator, for things like object
+    /// caches that get their memory straight from it.
+    pub fn frame_allocator(&mut self) -> &mut BitmapFrameAllocator {
+        &mut self.frame_allocator
+    }
+
+    /// free_frames returns how many frames of physical memory are free.
+    pub fn free_frames(&self) -> usize {
+        self.frame_allocator.free_frames()
+    }
+
+    /// used_frames returns how many frames of physical memory are in use.
+    pub fn used_frames(&self) -> usize {
+        self.frame_allocator.used_frames()
+    }
 }
 
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
@@ -124,13 +181,17 @@ pub struct Frame {
 }
 
 impl Frame {
-    fn containing_address(addr: usize) -> Frame {
+    /// containing_address returns the frame that contains the given physical
+    /// address. this should only be used by frame allocators, which are the
+    /// ones responsible for making sure a frame is only handed out once.
+    pub fn containing_address(addr: usize) -> Frame {
         Frame {
             number: addr / PAGE_SIZE,
         }
     }
 
-    fn start_address(&self) -> PhysicalAddress {
+    /// start_address returns the physical address of the start of the frame.
+    pub fn start_address(&self) -> PhysicalAddress {
         self.number * PAGE_SIZE
     }
 
@@ -143,7 +204,9 @@ impl Frame {
         Frame { number: self.number }
     }
 
-    fn range_inclusive(start: Frame, end: Frame) -> FrameIter {
+    /// range_inclusive returns an iterator from the start Frame to the end
+    /// Frame.
+    pub fn range_inclusive(start: Frame, end: Frame) -> FrameIter {
         FrameIter {
             start: start,
             end: end,
@@ -151,7 +214,7 @@ impl Frame {
     }
 }
 
-struct FrameIter {
+pub struct FrameIter {
     start: Frame,
     end: Frame,
 }
@@ -18,6 +18,11 @@ pub const PML4_SIZE: usize = 0x0000_0080_0000_0000;
 /// only has it's level 4 page table index
 pub const PML4_MASK: usize = 0x0000_ff80_0000_0000;
 
+/// KERNEL_SPACE_PML4_INDEX is the first entry of the upper half of the level 4
+/// page table. everything from here up belongs to the kernel, and every
+/// address space shares the same level 3 tables for it.
+pub const KERNEL_SPACE_PML4_INDEX: usize = 256;
+
 /// RECURSIVE_PAGE_OFFSET is the offset in the level 4 page table that contains
 /// the location of the recursive mapping. this is the way that redox defined
 /// it. I'm not sure if I'm a fan of the weird casting. it basically takes
@@ -34,13 +39,29 @@ pub const KERNEL_PML4_INDEX: usize = (KERNEL_OFFSET & PML4_MASK) / PML4_SIZE;
 /// offset to the kernel heap
 pub const KERNEL_HEAP_OFFSET: usize = KERNEL_OFFSET - PML4_SIZE;
 pub const KERNEL_HEAP_PML4_INDEX: usize = (KERNEL_HEAP_OFFSET & PML4_MASK) / PML4_SIZE;
-/// size of the kernel heap
+/// size the kernel heap starts out at
 pub const KERNEL_HEAP_SIZE: usize = 100 * 1024; // 100 KiB
+/// size the kernel heap can grow to. the rest of the entry is for stacks.
+pub const KERNEL_HEAP_MAX_SIZE: usize = PML4_SIZE / 2;
 
 /// offset to temporary pages for temporary things
 pub const KERNEL_TEMP_OFFSET: usize = KERNEL_HEAP_OFFSET - PML4_SIZE;
 pub const KERNEL_TEMP_PML4_INDEX: usize = (KERNEL_TEMP_OFFSET & PML4_MASK) / PML4_SIZE;
 
+/// offset to the kernel boot stack. the bootloader maps the stack the kernel
+/// starts out on here, one page up, so the page at the bottom is an unmapped
+/// guard page.
+pub const KERNEL_STACK_OFFSET: usize = KERNEL_TEMP_OFFSET - PML4_SIZE;
+pub const KERNEL_STACK_PML4_INDEX: usize = (KERNEL_STACK_OFFSET & PML4_MASK) / PML4_SIZE;
+/// size of the kernel boot stack, not counting the guard page
+pub const KERNEL_STACK_SIZE: usize = 64 * 1024; // 64 KiB
+
+/// offset to the uefi runtime regions. the bootloader maps every region the
+/// firmware needs at runtime here, at this offset plus its physical address,
+/// and tells the firmware about it with SetVirtualAddressMap.
+pub const UEFI_RUNTIME_OFFSET: usize = KERNEL_STACK_OFFSET - PML4_SIZE;
+pub const UEFI_RUNTIME_PML4_INDEX: usize = (UEFI_RUNTIME_OFFSET & PML4_MASK) / PML4_SIZE;
+
 /// offset to userspace. I will probably have to revisit this when I actually
 /// have a userspace.
 pub const USER_OFFSET: usize = 0;
@@ -0,0 +1,89 @@
+//! multiboot adapts the multiboot2 boot information the kernel used to be
+//! booted with into the boot protocol neutral types the rest of the crate uses.
+//! back then, the kernel was loaded at its physical address and mapped into
+//! the higher half at map::KERNEL_OFFSET plus that address.
+
+use kernel_image::{KernelImage, Section};
+use map;
+use multiboot2::{BootInformation, ElfSection};
+use paging::{EntryFlags, PhysicalAddress};
+use region::{MemoryRegion, MemoryRegionKind};
+use PAGE_SIZE;
+
+/// VGA_BUFFER is the physical address of the vga text buffer.
+const VGA_BUFFER: PhysicalAddress = 0xb8000;
+
+/// memory_regions returns the multiboot memory map as MemoryRegions. multiboot
+/// only tells us about memory we can use.
+pub fn memory_regions(boot_info: &BootInformation) -> impl Iterator<Item = MemoryRegion> + Clone {
+    let memory_map_tag = boot_info.memory_map_tag()
+        .expect("memory map tag required");
+
+    memory_map_tag.memory_areas().map(|area| MemoryRegion {
+        start: area.base_addr as usize,
+        end: (area.base_addr + area.length) as usize,
+        kind: MemoryRegionKind::Usable,
+    })
+}
+
+/// kernel_image describes the kernel from its elf sections. the vga text
+/// buffer and the multiboot information get mapped alongside it, the same way
+/// the kernel sections are, since the kernel used to find them there.
+pub fn kernel_image(boot_info: &BootInformation) -> KernelImage {
+    let elf_sections_tag = boot_info.elf_sections_tag()
+        .expect("elf-sections tag required");
+
+    let mut image = KernelImage::new();
+    for section in elf_sections_tag.sections() {
+        if !section.is_allocated() {
+            // section is not loaded into memory
+            continue;
+        }
+
+        assert!(section.start_address() % PAGE_SIZE == 0,
+                "sections need to be page aligned");
+
+        image.push(Section {
+            virt_start: map::KERNEL_OFFSET + section.start_address(),
+            phys_start: section.start_address(),
+            size: section.size as usize,
+            flags: section_flags(section),
+        });
+    }
+
+    image.push(Section {
+        virt_start: map::KERNEL_OFFSET + VGA_BUFFER,
+        phys_start: VGA_BUFFER,
+        size: PAGE_SIZE,
+        flags: EntryFlags::WRITABLE,
+    });
+
+    // the multiboot information is in the middle of usable memory, so this
+    // also keeps the frame allocator away from it.
+    image.push(Section {
+        virt_start: map::KERNEL_OFFSET + boot_info.start_address(),
+        phys_start: boot_info.start_address(),
+        size: boot_info.end_address() - boot_info.start_address(),
+        flags: EntryFlags::empty(),
+    });
+
+    image
+}
+
+/// section_flags converts elf section flags into their equivalent entry flags.
+fn section_flags(section: &ElfSection) -> EntryFlags {
+    use multiboot2::{ELF_SECTION_WRITABLE, ELF_SECTION_EXECUTABLE};
+
+    let mut flags = EntryFlags::empty();
+
+    if section.flags().contains(ELF_SECTION_WRITABLE) {
+        flags = flags | EntryFlags::WRITABLE;
+    }
+    if !section.flags().contains(ELF_SECTION_EXECUTABLE) {
+        // the section is NOT marked with the elf executable flag, so don't
+        // allow execution.
+        flags = flags | EntryFlags::NO_EXECUTE;
+    }
+
+    flags
+}
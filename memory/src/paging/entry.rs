@@ -1,7 +1,6 @@
 //! entry defines entries in the page table
 
 use Frame;
-use multiboot2::ElfSection;
 
 bitflags! {
     /// EntryFlags represents a set of flags that can be set for an entry in a
@@ -44,32 +43,6 @@ bitflags! {
     }
 }
 
-impl EntryFlags {
-    /// from_elf_section_flags uses the ElfSection struct from the multiboot2
-    /// crate to convert elf section flags into their equivalent entry flags.
-    /// this is used when identity mapping existing sections.
-    pub fn from_elf_section_flags(section: &ElfSection) -> EntryFlags {
-        use multiboot2::{ELF_SECTION_ALLOCATED, ELF_SECTION_WRITABLE, ELF_SECTION_EXECUTABLE};
-
-        let mut flags = EntryFlags::empty();
-
-        if section.flags().contains(ELF_SECTION_ALLOCATED) {
-            // section is loaded into memory
-            flags = flags | EntryFlags::PRESENT;
-        }
-        if section.flags().contains(ELF_SECTION_WRITABLE) {
-            flags = flags | EntryFlags::WRITABLE;
-        }
-        if !section.flags().contains(ELF_SECTION_EXECUTABLE) {
-            // the section is NOT marked with the elf executable flag, so don't
-            // allow execution.
-            flags = flags | EntryFlags::NO_EXECUTE;
-        }
-
-        flags
-    }
-}
-
 /// Entry represents an entry in a paging table. each table entry is 8 bytes (64
 /// bits). the exact meaning of the entry depends on the table it's in, and not
 /// all bits in the entry correspond to the address it's pointing at. a chart of
@@ -1,10 +1,56 @@
 //! mapper is the abstraction of a virtual to physical address map
 
+use core::arch::x86_64::__cpuid;
+use core::cmp;
 use core::ptr::Unique;
-use {PAGE_SIZE, Frame, FrameAllocator};
-use super::{VirtualAddress, PhysicalAddress, Page, ENTRY_COUNT};
+use {map, PAGE_SIZE, Frame, FrameAllocator};
+use super::{VirtualAddress, PhysicalAddress, Page, PageIter, ENTRY_COUNT};
 use super::entry::*;
-use super::table::{self, Table, Level4};
+use super::table::{self, Table, Level1, Level4};
+use x86_64::instructions::tlb;
+
+/// FLUSH_ALL_PAGES is how many pages a range has to cover before it's cheaper
+/// to flush the whole tlb than to flush each page in it.
+const FLUSH_ALL_PAGES: usize = 64;
+
+/// OutOfFrames is what the range functions fail with when the allocator runs
+/// out of frames partway through.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutOfFrames;
+
+/// PageSize is the size of a single mapping in the page tables. besides
+/// normal pages, a level 2 entry can map a 2MiB page and a level 3 entry can
+/// map a 1GiB page, in place of pointing at another table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageSize {
+    Size4KiB,
+    Size2MiB,
+    Size1GiB,
+}
+
+impl PageSize {
+    /// frames returns how many frames a page of this size covers.
+    pub fn frames(self) -> usize {
+        match self {
+            PageSize::Size4KiB => 1,
+            PageSize::Size2MiB => ENTRY_COUNT,
+            PageSize::Size1GiB => ENTRY_COUNT * ENTRY_COUNT,
+        }
+    }
+
+    /// bytes returns how big a page of this size is.
+    pub fn bytes(self) -> usize {
+        self.frames() * PAGE_SIZE
+    }
+}
+
+/// supports_1g_pages returns whether the cpu can map 1GiB pages, which it says
+/// with the PDPE1GB bit of the extended feature flags. every 64-bit cpu can
+/// map 2MiB ones.
+pub fn supports_1g_pages() -> bool {
+    let max_extended = unsafe { __cpuid(0x8000_0000) }.eax;
+    max_extended >= 0x8000_0001 && unsafe { __cpuid(0x8000_0001) }.edx & (1 << 26) != 0
+}
 
 /// Mapper represents a set of page tables able to map virtual addresses to
 /// physical ones. it provides the ability to translate virtual addresses, as
@@ -39,50 +85,41 @@ impl Mapper {
             .map(|frame| frame.number * PAGE_SIZE + offset)
     }
 
-    /// translate_page translates a virtual Page to a physical Frame. there is a
-    /// cursory implementation of translating huge pages because our initial
-    /// page tables set up in memory use them, but for the most part it just
-    /// uses the table indexes in the Page number to traverse the page tables
-    /// and return the mapped physical frame. if at any point it doesn't find a
+    /// translate_page translates a virtual Page to a physical Frame. the page
+    /// can be part of a huge page, in which case it's the frame inside the
+    /// huge page that lines up with it. if at any point it doesn't find a
     /// corresponding entry, it returns None.
     pub fn translate_page(&self, page: Page) -> Option<Frame> {
-        let p3 = self.p4().next_table(page.p4_index());
+        self.mapping(page).map(|(frame, size)| Frame {
+            number: frame.number + page.number % size.frames(),
+        })
+    }
 
-        let huge_page = || {
-            p3.and_then(|p3| {
-                let p3_entry = &p3[page.p3_index()];
-                // 1GiB page?
-                if let Some(start_frame) = p3_entry.pointed_frame() {
-                    if p3_entry.flags().contains(EntryFlags::HUGE_PAGE) {
-                        // address must be 1GiB aligned
-                        assert!(start_frame.number % (ENTRY_COUNT * ENTRY_COUNT) == 0);
-                        return Some(Frame {
-                            number: start_frame.number + page.p2_index() *
-                                ENTRY_COUNT + page.p1_index(),
-                        });
-                    }
-                }
-                if let Some(p2) = p3.next_table(page.p3_index()) {
-                    let p2_entry = &p2[page.p2_index()];
-                    // 2MiB page?
-                    if let Some(start_frame) = p2_entry.pointed_frame() {
-                        if p2_entry.flags().contains(EntryFlags::HUGE_PAGE) {
-                            // address must be 2MiB aligned
-                            assert!(start_frame.number % ENTRY_COUNT == 0);
-                            return Some(Frame {
-                                number: start_frame.number + page.p1_index(),
-                            });
-                        }
-                    }
-                }
-                None
-            })
-        };
+    /// mapping returns the first frame and the size of the mapping the page
+    /// is part of, if it's mapped. for normal pages that's just the frame it's
+    /// mapped to.
+    pub fn mapping(&self, page: Page) -> Option<(Frame, PageSize)> {
+        let p3 = self.p4().next_table(page.p4_index())?;
+
+        let p3_entry = &p3[page.p3_index()];
+        if p3_entry.flags().contains(EntryFlags::HUGE_PAGE) {
+            let start_frame = p3_entry.pointed_frame()?;
+            // address must be 1GiB aligned
+            assert!(start_frame.number % PageSize::Size1GiB.frames() == 0);
+            return Some((start_frame, PageSize::Size1GiB));
+        }
+
+        let p2 = p3.next_table(page.p3_index())?;
+        let p2_entry = &p2[page.p2_index()];
+        if p2_entry.flags().contains(EntryFlags::HUGE_PAGE) {
+            let start_frame = p2_entry.pointed_frame()?;
+            // address must be 2MiB aligned
+            assert!(start_frame.number % PageSize::Size2MiB.frames() == 0);
+            return Some((start_frame, PageSize::Size2MiB));
+        }
 
-        p3.and_then(|p3| p3.next_table(page.p3_index()))
-            .and_then(|p2| p2.next_table(page.p2_index()))
-            .and_then(|p1| p1[page.p1_index()].pointed_frame())
-            .or_else(huge_page)
+        let p1 = p2.next_table(page.p2_index())?;
+        p1[page.p1_index()].pointed_frame().map(|frame| (frame, PageSize::Size4KiB))
     }
 
     /// map_to takes a virtual Page and maps it to a physical Frame in our
@@ -107,6 +144,139 @@ impl Mapper {
         p1[page.p1_index()].set(frame, flags | EntryFlags::PRESENT);
     }
 
+    /// map_to_huge_2m maps a 2MiB page, starting at the given page, to the 2MiB
+    /// of physical memory starting at the given frame. both have to be 2MiB
+    /// aligned. like map_to, it creates any page tables that don't exist yet,
+    /// and asserts that nothing is mapped there already.
+    pub fn map_to_huge_2m<A>(
+        &mut self,
+        page: Page,
+        frame: Frame,
+        flags: EntryFlags,
+        allocator: &mut A,
+    )
+        where A: FrameAllocator
+    {
+        let frames = PageSize::Size2MiB.frames();
+        assert!(page.number % frames == 0 && frame.number % frames == 0,
+                "2MiB pages have to be 2MiB aligned");
+
+        let p4 = self.p4_mut();
+        let p3 = p4.next_table_create(page.p4_index(), allocator);
+        let p2 = p3.next_table_create(page.p3_index(), allocator);
+
+        assert!(p2[page.p2_index()].is_unused());
+        p2[page.p2_index()].set(frame, flags | EntryFlags::PRESENT | EntryFlags::HUGE_PAGE);
+    }
+
+    /// map_to_huge_1g maps a 1GiB page, starting at the given page, to the 1GiB
+    /// of physical memory starting at the given frame. both have to be 1GiB
+    /// aligned, and the cpu has to support 1GiB pages.
+    pub fn map_to_huge_1g<A>(
+        &mut self,
+        page: Page,
+        frame: Frame,
+        flags: EntryFlags,
+        allocator: &mut A,
+    )
+        where A: FrameAllocator
+    {
+        assert!(supports_1g_pages(), "the cpu doesn't support 1GiB pages");
+        let frames = PageSize::Size1GiB.frames();
+        assert!(page.number % frames == 0 && frame.number % frames == 0,
+                "1GiB pages have to be 1GiB aligned");
+
+        let p4 = self.p4_mut();
+        let p3 = p4.next_table_create(page.p4_index(), allocator);
+
+        assert!(p3[page.p3_index()].is_unused());
+        p3[page.p3_index()].set(frame, flags | EntryFlags::PRESENT | EntryFlags::HUGE_PAGE);
+    }
+
+    /// map_physical_range maps size bytes of physical memory starting at phys
+    /// to virtual memory starting at virt, which have to have the same offset
+    /// into their pages. it uses the biggest pages it can. anywhere both
+    /// addresses are aligned to a huge page, the rest of the range covers all
+    /// of it, and nothing is mapped there yet, it maps a huge page instead of
+    /// a table full of small ones. that makes big ranges like a direct map of
+    /// physical memory or the framebuffer a lot cheaper in tables and tlb
+    /// entries.
+    pub fn map_physical_range<A>(
+        &mut self,
+        virt: VirtualAddress,
+        phys: PhysicalAddress,
+        size: usize,
+        flags: EntryFlags,
+        allocator: &mut A,
+    )
+        where A: FrameAllocator
+    {
+        assert!(virt % PAGE_SIZE == phys % PAGE_SIZE,
+                "virtual and physical addresses have different page offsets");
+        if size == 0 {
+            return;
+        }
+
+        let huge_1g = supports_1g_pages();
+        let mut page = Page::containing_address(virt);
+        let mut frame = Frame::containing_address(phys);
+        let mut remaining = (virt + size - 1) / PAGE_SIZE - page.number + 1;
+
+        while remaining > 0 {
+            let size = self.best_page_size(page, &frame, remaining, huge_1g);
+            let next = Frame { number: frame.number + size.frames() };
+            match size {
+                PageSize::Size1GiB => self.map_to_huge_1g(page, frame, flags, allocator),
+                PageSize::Size2MiB => self.map_to_huge_2m(page, frame, flags, allocator),
+                PageSize::Size4KiB => self.map_to(page, frame, flags, allocator),
+            }
+
+            page = page + size.frames();
+            frame = next;
+            remaining -= size.frames();
+        }
+    }
+
+    /// identity_map_range maps size bytes of physical memory starting at phys
+    /// to the same virtual addresses, using huge pages where it can.
+    pub fn identity_map_range<A>(
+        &mut self,
+        phys: PhysicalAddress,
+        size: usize,
+        flags: EntryFlags,
+        allocator: &mut A,
+    )
+        where A: FrameAllocator
+    {
+        self.map_physical_range(phys, phys, size, flags, allocator)
+    }
+
+    /// best_page_size returns the biggest page map_physical_range can use to
+    /// map the page to the frame, with remaining pages left to map.
+    fn best_page_size(&self, page: Page, frame: &Frame, remaining: usize, huge_1g: bool)
+                      -> PageSize
+    {
+        let p3 = self.p4().next_table(page.p4_index());
+
+        let frames = PageSize::Size1GiB.frames();
+        if huge_1g && page.number % frames == 0 && frame.number % frames == 0 &&
+            remaining >= frames &&
+            p3.map_or(true, |p3| p3[page.p3_index()].is_unused())
+        {
+            return PageSize::Size1GiB;
+        }
+
+        let frames = PageSize::Size2MiB.frames();
+        if page.number % frames == 0 && frame.number % frames == 0 && remaining >= frames &&
+            p3.and_then(|p3| p3.next_table(page.p3_index()))
+                .map_or(true, |p2| p2[page.p2_index()].is_unused())
+        {
+            return PageSize::Size2MiB;
+        }
+
+        PageSize::Size4KiB
+    }
+
     /// map takes a virtual Page and maps it to the next available spot in
     /// memory, as provided by the provided allocator.
     pub fn map<A>(&mut self, page: Page, flags: EntryFlags, allocator: &mut A)
@@ -145,33 +315,338 @@ impl Mapper {
         self.map_to(page, frame, flags, allocator)
     }
 
+    /// map_range maps every page in pages to a fresh frame from the allocator.
+    /// it walks down to each level 1 table once, instead of once per page. if
+    /// the allocator runs out of frames partway through, it unmaps everything
+    /// it mapped and frees everything it allocated before failing, so it's
+    /// like it was never called.
+    pub fn map_range<A>(&mut self, pages: PageIter, flags: EntryFlags, allocator: &mut A)
+                        -> Result<(), OutOfFrames>
+        where A: FrameAllocator
+    {
+        self.map_range_with(pages, flags, allocator, true, |allocator, _| allocator.allocate_frame())
+    }
+
+    /// map_range_to maps every page in pages to the frames starting at frame,
+    /// in order, like map_range does. the frames aren't the allocator's, so
+    /// if it runs out of frames for page tables partway through, only the
+    /// tables are freed.
+    pub fn map_range_to<A>(
+        &mut self,
+        pages: PageIter,
+        frame: Frame,
+        flags: EntryFlags,
+        allocator: &mut A,
+    ) -> Result<(), OutOfFrames>
+        where A: FrameAllocator
+    {
+        let first = frame.number;
+        self.map_range_with(pages, flags, allocator, false,
+                            move |_, i| Some(Frame { number: first + i }))
+    }
+
+    /// unmap_range unmaps every page in pages, and frees the frames they were
+    /// mapped to and any tables that are left empty. huge pages in the range
+    /// are unmapped whole, and have to be entirely inside it. the tlb is
+    /// flushed once at the end, instead of once per page.
+    pub fn unmap_range<A>(&mut self, pages: PageIter, allocator: &mut A)
+        where A: FrameAllocator
+    {
+        self.unmap_range_with(pages, allocator, true)
+    }
+
+    /// protect_range changes the flags of every page in pages, which all have
+    /// to be mapped, to flags. huge pages in the range have to be entirely
+    /// inside it. the tlb is flushed once at the end.
+    pub fn protect_range(&mut self, pages: PageIter, flags: EntryFlags) {
+        let (start, end) = (pages.start, pages.end);
+        let mut page = start;
+        while page <= end {
+            let (frame, size) = self.mapping(page)
+                .expect("can't protect a page that isn't mapped");
+            let count = match size {
+                PageSize::Size4KiB => {
+                    let count = cmp::min(end.number, page.number | (ENTRY_COUNT - 1)) -
+                        page.number + 1;
+                    let p1 = self.p1_mut(page).unwrap();
+                    for i in page.p1_index()..page.p1_index() + count {
+                        let frame = p1[i].pointed_frame()
+                            .expect("can't protect a page that isn't mapped");
+                        p1[i].set(frame, flags | EntryFlags::PRESENT);
+                    }
+                    count
+                },
+                _ => {
+                    assert_whole_page(page, end, size);
+                    self.leaf_entry_mut(page, size)
+                        .set(frame, flags | EntryFlags::PRESENT | EntryFlags::HUGE_PAGE);
+                    size.frames()
+                },
+            };
+            page = page + count;
+        }
+
+        flush_range(start, end);
+    }
+
+    /// update_flags changes the flags of a page that is already mapped, without
+    /// changing the frame it's mapped to, and flushes it from the tlb. the page
+    /// can be the start of a huge page, in which case the flags of the whole
+    /// huge page change. it returns the flags the page had before. it panics
+    /// if the page isn't mapped, or is in the middle of a huge page.
+    pub fn update_flags(&mut self, page: Page, flags: EntryFlags) -> EntryFlags {
+        let (frame, size) = self.mapping(page)
+            .expect("can't change the flags of a page that isn't mapped");
+        assert!(page.number % size.frames() == 0,
+                "can't change the flags of part of a huge page");
+
+        let flags = match size {
+            PageSize::Size4KiB => flags | EntryFlags::PRESENT,
+            _ => flags | EntryFlags::PRESENT | EntryFlags::HUGE_PAGE,
+        };
+        let old_flags = {
+            let entry = self.leaf_entry_mut(page, size);
+            let old_flags = entry.flags();
+            entry.set(frame, flags);
+            old_flags
+        };
+
+        tlb::flush(::x86_64::VirtualAddress(page.start_address()));
+        old_flags
+    }
+
     /// unmap sets the entry defined by the provided virtual Page to be unused.
-    /// it asserts that it is currently mapped. it panics if it fails to get the
-    /// next table and doesn't currently support huge pages. once it sets the
-    /// entry as unused it flushes the tlb and deallocates the frame.
+    /// it asserts that it is currently mapped. the page can be the start of a
+    /// huge page, in which case the whole huge page is unmapped. once it sets
+    /// the entry as unused it flushes the tlb and deallocates the frames.
+    pub fn unmap<A>(&mut self, page: Page, allocator: &mut A)
+        where A: FrameAllocator
+    {
+        let (frame, size) = self.unmap_frame(page, allocator);
+        for number in frame.number..frame.number + size.frames() {
+            allocator.deallocate_frame(Frame { number: number });
+        }
+    }
+
+    /// unmap_frame unmaps the page like unmap, but hands the first frame it
+    /// was mapped to and the size of the mapping back instead of deallocating
+    /// them, for when the frames aren't ours to free, like the one behind a
+    /// temporary page. it panics if the page is in the middle of a huge page,
+    /// since we can't unmap part of one.
     ///
-    /// currently it doesn't actually deallocate the frame, since our current
-    /// main memory allocator doesn't implement deallocation and instead just
-    /// leaks the memory.
-    pub fn unmap<A>(&mut self, page: Page, _allocator: &mut A)
+    /// any page tables that are left empty are freed with the allocator, all
+    /// the way up to the level 3 table. the level 4 table itself is never
+    /// freed, since it's the whole address space.
+    pub fn unmap_frame<A>(&mut self, page: Page, allocator: &mut A) -> (Frame, PageSize)
         where A: FrameAllocator
     {
-        assert!(self.translate(page.start_address()).is_some());
+        let (frame, size) = self.mapping(page)
+            .expect("can't unmap a page that isn't mapped");
+        assert!(page.number % size.frames() == 0, "can't unmap part of a huge page");
 
-        let p1 = self.p4_mut()
+        self.leaf_entry_mut(page, size).set_unused();
+        self.reclaim_tables(page, size, allocator);
+
+        // invalidating any address in a huge page invalidates all of it, and
+        // this also gets rid of anything cached about the tables we freed.
+        tlb::flush(::x86_64::VirtualAddress(page.start_address()));
+
+        (frame, size)
+    }
+
+    /// map_range_with does the work of map_range and map_range_to. next_frame
+    /// returns the frame to map the i-th page to. owned is whether those
+    /// frames should be freed if we have to back out.
+    fn map_range_with<A, F>(
+        &mut self,
+        pages: PageIter,
+        flags: EntryFlags,
+        allocator: &mut A,
+        owned: bool,
+        mut next_frame: F,
+    ) -> Result<(), OutOfFrames>
+        where A: FrameAllocator,
+              F: FnMut(&mut A, usize) -> Option<Frame>
+    {
+        let (start, end) = (pages.start, pages.end);
+        let mut page = start;
+        let mut mapped = 0;
+        while page <= end {
+            // everything up to the end of the range or this level 1 table,
+            // whichever comes first
+            let last = cmp::min(end.number, page.number | (ENTRY_COUNT - 1));
+            let complete = match self.p1_create(page, allocator) {
+                Some(p1) => {
+                    let mut complete = true;
+                    for number in page.number..last + 1 {
+                        match next_frame(&mut *allocator, mapped) {
+                            Some(frame) => {
+                                let entry = &mut p1[number % ENTRY_COUNT];
+                                assert!(entry.is_unused());
+                                entry.set(frame, flags | EntryFlags::PRESENT);
+                                mapped += 1;
+                            },
+                            None => {
+                                complete = false;
+                                break;
+                            },
+                        }
+                    }
+                    complete
+                },
+                None => false,
+            };
+
+            if !complete {
+                if mapped > 0 {
+                    let done = Page::range_inclusive(start, start + (mapped - 1));
+                    self.unmap_range_with(done, allocator, owned);
+                }
+                // we might have made tables for this bit without getting to
+                // put anything in them.
+                self.reclaim_tables(page, PageSize::Size4KiB, allocator);
+                return Err(OutOfFrames);
+            }
+
+            page = Page { number: last + 1 };
+        }
+
+        Ok(())
+    }
+
+    /// unmap_range_with does the work of unmap_range. free_frames is whether
+    /// to free the frames the pages were mapped to.
+    fn unmap_range_with<A>(&mut self, pages: PageIter, allocator: &mut A, free_frames: bool)
+        where A: FrameAllocator
+    {
+        let (start, end) = (pages.start, pages.end);
+        let mut page = start;
+        while page <= end {
+            let (frame, size) = self.mapping(page)
+                .expect("can't unmap a page that isn't mapped");
+            let count = match size {
+                PageSize::Size4KiB => {
+                    let count = cmp::min(end.number, page.number | (ENTRY_COUNT - 1)) -
+                        page.number + 1;
+                    let p1 = self.p1_mut(page).unwrap();
+                    for i in page.p1_index()..page.p1_index() + count {
+                        let frame = p1[i].pointed_frame()
+                            .expect("can't unmap a page that isn't mapped");
+                        p1[i].set_unused();
+                        if free_frames {
+                            allocator.deallocate_frame(frame);
+                        }
+                    }
+                    count
+                },
+                _ => {
+                    assert_whole_page(page, end, size);
+                    self.leaf_entry_mut(page, size).set_unused();
+                    if free_frames {
+                        for number in frame.number..frame.number + size.frames() {
+                            allocator.deallocate_frame(Frame { number: number });
+                        }
+                    }
+                    size.frames()
+                },
+            };
+
+            self.reclaim_tables(page, size, allocator);
+            page = page + count;
+        }
+
+        flush_range(start, end);
+    }
+
+    /// p1_mut returns the level 1 table the page is in, if there is one.
+    fn p1_mut(&mut self, page: Page) -> Option<&mut Table<Level1>> {
+        self.p4_mut()
             .next_table_mut(page.p4_index())
             .and_then(|p3| p3.next_table_mut(page.p3_index()))
             .and_then(|p2| p2.next_table_mut(page.p2_index()))
-            .expect("mapping code does not support huge pages");
-        let _frame = p1[page.p1_index()].pointed_frame().unwrap();
-        p1[page.p1_index()].set_unused();
+    }
 
-        use x86_64::instructions::tlb;
-        use x86_64::VirtualAddress;
-        tlb::flush(VirtualAddress(page.start_address()));
+    /// p1_create returns the level 1 table the page is in, creating it and
+    /// the tables above it if they don't exist. it returns None if the
+    /// allocator runs out of frames for them.
+    fn p1_create<A>(&mut self, page: Page, allocator: &mut A) -> Option<&mut Table<Level1>>
+        where A: FrameAllocator
+    {
+        let p3 = self.p4_mut().try_next_table_create(page.p4_index(), allocator)?;
+        let p2 = p3.try_next_table_create(page.p3_index(), allocator)?;
+        p2.try_next_table_create(page.p2_index(), allocator)
+    }
+
+    /// leaf_entry_mut returns the entry that maps the page, which is mapped
+    /// as a page of the given size. for huge pages, that's the entry in the
+    /// level 2 or level 3 table.
+    fn leaf_entry_mut(&mut self, page: Page, size: PageSize) -> &mut Entry {
+        let p3 = self.p4_mut().next_table_mut(page.p4_index()).unwrap();
+        match size {
+            PageSize::Size1GiB => &mut p3[page.p3_index()],
+            PageSize::Size2MiB => &mut p3.next_table_mut(page.p3_index()).unwrap()[page.p2_index()],
+            PageSize::Size4KiB => {
+                let p1 = p3.next_table_mut(page.p3_index())
+                    .and_then(|p2| p2.next_table_mut(page.p2_index()))
+                    .unwrap();
+                &mut p1[page.p1_index()]
+            },
+        }
+    }
+
+    /// reclaim_tables frees the tables above a page of the given size that
+    /// was just unmapped, from the bottom up, stopping at the first one that
+    /// still has something in it. the level 3 tables of the kernel half are
+    /// never freed, since every address space points at the same ones, and
+    /// freeing one would quietly cut the others off from that part of it.
+    fn reclaim_tables<A>(&mut self, page: Page, size: PageSize, allocator: &mut A)
+        where A: FrameAllocator
+    {
+        {
+            let p3 = match self.p4_mut().next_table_mut(page.p4_index()) {
+                Some(p3) => p3,
+                None => return,
+            };
+            if size == PageSize::Size4KiB {
+                if let Some(p2) = p3.next_table_mut(page.p3_index()) {
+                    if !p2.reclaim_next_table(page.p2_index(), allocator) {
+                        return;
+                    }
+                }
+            }
+            if size != PageSize::Size1GiB && !p3.reclaim_next_table(page.p3_index(), allocator) {
+                return;
+            }
+        }
 
-        // TODO free p(1,2,3) table if empty
-        // TODO implement deallocate_frame
-        // allocator.deallocate_frame(frame);
+        if page.p4_index() >= map::KERNEL_SPACE_PML4_INDEX {
+            return;
+        }
+
+        self.p4_mut().reclaim_next_table(page.p4_index(), allocator);
+    }
+}
+
+/// assert_whole_page asserts that the huge page of the given size starting at
+/// page is the start of one and ends by end, since the range functions can't
+/// do anything with part of one.
+fn assert_whole_page(page: Page, end: Page, size: PageSize) {
+    assert!(page.number % size.frames() == 0 && page.number + size.frames() - 1 <= end.number,
+            "can't change part of a huge page");
+}
+
+/// flush_range flushes the pages from start to end from the tlb, or the whole
+/// tlb if that's cheaper.
+fn flush_range(start: Page, end: Page) {
+    if start > end {
+        return;
+    }
+
+    if end.number - start.number + 1 > FLUSH_ALL_PAGES {
+        tlb::flush_all();
+    } else {
+        for page in Page::range_inclusive(start, end) {
+            tlb::flush(::x86_64::VirtualAddress(page.start_address()));
+        }
     }
 }
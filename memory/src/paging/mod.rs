@@ -6,12 +6,12 @@ mod table;
 mod temporary_page;
 
 pub use self::entry::*;
-pub use self::mapper::Mapper;
+pub use self::mapper::{supports_1g_pages, Mapper, OutOfFrames, PageSize};
 
 use map;
 use core::ops::{Add, Deref, DerefMut};
 use {PAGE_SIZE, Frame, FrameAllocator};
-use multiboot2::BootInformation;
+use kernel_image::KernelImage;
 use self::temporary_page::TemporaryPage;
 
 /// ENTRY_COUNT defines the number of entries in every page table.
@@ -21,15 +21,15 @@ pub type PhysicalAddress = usize;
 pub type VirtualAddress = usize;
 
 /// init initializes the paging that will actually be used by the kernel during
-/// normal runtime. the assembly code that runs on startup sets up an extremely
-/// simple set of page tables that point at huge pages (which we don't currently
-/// fully support) and isn't very fleshed out. we also rely on identity mapping
-/// for the initial kernel and stack execution, since it is the easiest way to
-/// boot up, but during normal execution I would like to have the kernel mapped
-/// in the higher half of memory.
+/// normal runtime. whatever booted us left us with page tables that have the
+/// kernel mapped somewhere, along with whatever else it thought we needed, like
+/// an identity mapping of low memory or the firmware's runtime regions. we
+/// build a fresh set of tables that keep all of that, except for the kernel's
+/// own pml4 slot, which gets rebuilt from the kernel image with the right
+/// permissions for every section, in frames that belong to us.
 pub fn init<A>(
     allocator: &mut A,
-    boot_info: &BootInformation,
+    kernel: &KernelImage,
 ) -> ActivePageTable
     where A: FrameAllocator
 {
@@ -43,6 +43,22 @@ pub fn init<A>(
 
     // initialize what will be the single active page table reference
     let mut active_table = unsafe { ActivePageTable::new() };
+
+    // remember the pml4 entries we are keeping. the lower level tables they
+    // point at are shared between the old tables and the new ones. that
+    // includes the bootloader's identity mapping of physical memory, which
+    // is load-bearing: the frame allocators and the slab caches get at the
+    // memory they manage through its physical address.
+    let mut inherited = [None; ENTRY_COUNT];
+    for (i, entry) in inherited.iter_mut().enumerate() {
+        if i == map::KERNEL_PML4_INDEX || i == map::RECURSIVE_PAGE_PML4_INDEX {
+            continue;
+        }
+        let old_entry = &active_table.p4()[i];
+        *entry = old_entry.pointed_frame()
+            .map(|frame| (frame.start_address(), old_entry.flags()));
+    }
+
     // make a new table for our actual runtime mapping
     let mut new_table = {
         let frame = allocator.allocate_frame().expect("no more frames");
@@ -51,58 +67,33 @@ pub fn init<A>(
 
     // setup the new table
     active_table.with(&mut new_table, &mut temporary_page, |mapper| {
-        let elf_sections_tag = boot_info.elf_sections_tag()
-            .expect("memory map tag required");
-
-        for section in elf_sections_tag.sections() {
-            use self::entry::EntryFlags;
-            if !section.is_allocated() {
-                // section is not loaded into memory
-                continue;
-            }
-
-            assert!(section.start_address() % PAGE_SIZE == 0,
-                    "sections need to be page aligned");
-
-            info!("mapping section at addr: {:#x}, size: {:#x}",
-                     section.addr, section.size);
-
-            let flags = EntryFlags::from_elf_section_flags(section);
-
-            let start_frame = Frame::containing_address(section.start_address());
-            let end_frame = Frame::containing_address(section.end_address() - 1);
-            for frame in Frame::range_inclusive(start_frame, end_frame) {
-                mapper.identity_map_offset(map::KERNEL_OFFSET, frame, flags, allocator);
+        for (i, entry) in inherited.iter().enumerate() {
+            if let Some((addr, flags)) = *entry {
+                mapper.p4_mut()[i].set(Frame::containing_address(addr), flags);
             }
         }
 
-        // identity map the VGA text buffer
-        let vga_buffer_frame = Frame::containing_address(0xb8000);
-        mapper.identity_map_offset(map::KERNEL_OFFSET,
-                                   vga_buffer_frame,
-                                   EntryFlags::WRITABLE,
-                                   allocator);
-
-        // identity map the multiboot info structure
-        let multiboot_start = Frame::containing_address(boot_info.start_address());
-        let multiboot_end = Frame::containing_address(boot_info.end_address() - 1);
-        for frame in Frame::range_inclusive(multiboot_start, multiboot_end) {
-            mapper.identity_map_offset(map::KERNEL_OFFSET,
-                                       frame,
-                                       EntryFlags::PRESENT,
-                                       allocator);
+        for section in kernel.sections() {
+            info!("mapping section at addr: {:#x}, size: {:#x}",
+                  section.virt_start, section.size);
+
+            let start_page = Page::containing_address(section.virt_start);
+            let end_page = Page::containing_address(section.virt_start + section.size - 1);
+            let frame = Frame::containing_address(section.phys_start);
+            mapper.map_range_to(Page::range_inclusive(start_page, end_page), frame,
+                                section.flags, allocator)
+                .expect("not enough frames to map the kernel");
         }
     });
 
-    // switch to the new table
-    let old_table = active_table.switch(new_table);
+    // switch to the new table. the old p4 table is leaked, since nothing can
+    // give frames back yet.
+    let p4_addr = new_table.p4_frame.start_address();
+    active_table.switch(new_table);
 
-    // turn the old p4 page into a guard page for some basic stack overflow
-    // protections. a guard page is just an unallocated (and unallocatable) page
-    // that gets stomped on if something overruns the stack, which triggers a
-    // page fault.
-    let old_p4_page = Page::containing_address(old_table.p4_frame.start_address());
-    active_table.unmap(old_p4_page, allocator);
+    assert_eq!(active_table.translate(p4_addr), Some(p4_addr),
+               "physical memory isn't identity mapped anymore, the frame allocators and \
+                slab caches can't get at their memory");
 
     active_table
 }
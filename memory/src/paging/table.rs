@@ -2,6 +2,8 @@
 
 use core::marker::PhantomData;
 use core::ops::{Index, IndexMut};
+use x86_64::instructions::tlb;
+use x86_64::VirtualAddress;
 use FrameAllocator;
 use super::entry::*;
 use super::ENTRY_COUNT;
@@ -45,6 +47,11 @@ impl<L> Table<L> where L: TableLevel
             entry.set_unused();
         }
     }
+
+    /// is_empty returns whether every entry in the table is unused.
+    pub fn is_empty(&self) -> bool {
+        self.entries.iter().all(|entry| entry.is_unused())
+    }
 }
 
 impl<L> Table<L> where L: HierarchicalLevel
@@ -79,16 +86,52 @@ impl<L> Table<L> where L: HierarchicalLevel
         allocator: &mut A
     ) -> &mut Table<L::NextLevel>
         where A: FrameAllocator
+    {
+        self.try_next_table_create(index, allocator).expect("no frames available")
+    }
+
+    /// try_next_table_create is next_table_create, except it returns None
+    /// instead of panicking if the allocator is out of frames.
+    pub fn try_next_table_create<A>(
+        &mut self,
+        index: usize,
+        allocator: &mut A
+    ) -> Option<&mut Table<L::NextLevel>>
+        where A: FrameAllocator
     {
         if self.next_table(index).is_none() {
             assert!(!self.entries[index].flags().contains(EntryFlags::HUGE_PAGE),
-                    "mapping code does not support huge pages");
-            let frame = allocator.allocate_frame().expect("no frames available");
+                    "there is already a huge page mapped there");
+            let frame = allocator.allocate_frame()?;
             self.entries[index].set(frame, EntryFlags::PRESENT | EntryFlags::WRITABLE);
             self.next_table_mut(index).unwrap().zero();
         }
-        // we just created the table, so unwrapping is fine
-        self.next_table_mut(index).unwrap()
+        // we just created the table if it wasn't there, so it's there now
+        self.next_table_mut(index)
+    }
+
+    /// reclaim_next_table frees the table the entry at index points to, if
+    /// every entry in it is unused. it clears the entry, flushes the table's
+    /// recursive mapping from the tlb, and gives the table's frame back to
+    /// the allocator. it returns whether the entry is unused now, which is
+    /// also the case if there was no table there to begin with.
+    pub fn reclaim_next_table<A>(&mut self, index: usize, allocator: &mut A) -> bool
+        where A: FrameAllocator
+    {
+        if self.entries[index].is_unused() {
+            return true;
+        }
+
+        let table_address = match self.next_table(index) {
+            Some(table) if table.is_empty() => table as *const _ as usize,
+            _ => return false,
+        };
+
+        let frame = self.entries[index].pointed_frame().unwrap();
+        self.entries[index].set_unused();
+        tlb::flush(VirtualAddress(table_address));
+        allocator.deallocate_frame(frame);
+        true
     }
 }
 
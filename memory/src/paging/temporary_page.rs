@@ -78,9 +78,13 @@ impl TemporaryPage {
         self.page.start_address()
     }
 
-    /// unmaps the temporary page in the active table
+    /// unmaps the temporary page in the active table. the frame it was mapped
+    /// to belongs to whoever asked for it to be mapped, so it isn't freed. the
+    /// tables it needed are, and they go back to the tiny allocator, which is
+    /// where they came from. the level 3 table is part of the kernel half, so
+    /// it stays, and the next map doesn't need it again.
     pub fn unmap(&mut self, active_table: &mut ActivePageTable) {
-        active_table.unmap(self.page, &mut self.allocator)
+        active_table.unmap_frame(self.page, &mut self.allocator);
     }
 
     /// maps the temporary page to the given page table frame in the active
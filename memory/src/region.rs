@@ -0,0 +1,52 @@
+//! region describes physical memory in a way that doesn't care how we were
+//! booted. whatever the bootloader hands us gets turned into a list of these
+//! before the rest of the crate sees it.
+
+use paging::PhysicalAddress;
+use {Frame, PAGE_SIZE};
+
+/// MemoryRegion is a range of physical memory, and what it's being used for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryRegion {
+    /// start is the physical address of the start of the region.
+    pub start: PhysicalAddress,
+    /// end is the physical address one past the end of the region.
+    pub end: PhysicalAddress,
+    /// kind says what the region is for.
+    pub kind: MemoryRegionKind,
+}
+
+/// MemoryRegionKind is what a region of physical memory is for, as far as the
+/// memory manager is concerned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryRegionKind {
+    /// free memory we can do whatever we want with.
+    Usable,
+    /// memory the bootloader put things in for us, like the kernel and the
+    /// boot information. it's ours once we are done with what is in it.
+    Bootloader,
+    /// memory the firmware was using while booting, which we can have once
+    /// we are sure nothing in it is still needed.
+    Reclaimable,
+    /// memory we should never touch.
+    Reserved,
+}
+
+impl MemoryRegion {
+    /// is_usable returns whether the region is free to allocate from.
+    pub fn is_usable(&self) -> bool {
+        self.kind == MemoryRegionKind::Usable
+    }
+
+    /// start_frame returns the first whole frame in the region. regions aren't
+    /// necessarily page aligned, and we can't hand out part of a frame.
+    pub fn start_frame(&self) -> Frame {
+        Frame::containing_address(self.start + PAGE_SIZE - 1)
+    }
+
+    /// end_frame returns the frame one past the last whole frame in the
+    /// region.
+    pub fn end_frame(&self) -> Frame {
+        Frame::containing_address(self.end)
+    }
+}
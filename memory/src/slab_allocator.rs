@@ -0,0 +1,327 @@
+//! slab_allocator hands out lots of objects of the same size quickly, without
+//! the overhead or fragmentation of the heap. a cache gets whole frames from
+//! the frame allocator, called slabs, and cuts each of them up into as many
+//! objects as fit. freed objects go back on their slab's free list, and a
+//! slab with nothing allocated out of it can be given back to the frame
+//! allocator.
+//!
+//! every slab is a single frame, with its header at the start, so finding the
+//! slab an object came from is just rounding its address down. like the frame
+//! allocators, slabs are accessed at their physical address, relying on
+//! physical memory being identity mapped.
+
+use core::marker::PhantomData;
+use core::ptr::{self, NonNull};
+use core::{cmp, mem};
+use heap_allocator::align_up;
+use {Frame, FrameAllocator, PAGE_SIZE};
+
+/// MAX_OBJECT_SIZE is the biggest object a slab cache will hold. past this,
+/// too much of every slab goes to waste.
+pub const MAX_OBJECT_SIZE: usize = PAGE_SIZE / 8;
+
+/// Slab is the header at the start of every slab.
+struct Slab {
+    prev: *mut Slab,
+    next: *mut Slab,
+    /// free is the first free object in the slab.
+    free: *mut FreeObject,
+    /// in_use is how many objects are allocated out of the slab.
+    in_use: usize,
+}
+
+/// FreeObject sits at the start of every free object, and links it into its
+/// slab's free list.
+struct FreeObject {
+    next: *mut FreeObject,
+}
+
+/// SlabList is a list of slabs, linked through their headers.
+struct SlabList {
+    head: *mut Slab,
+    len: usize,
+}
+
+impl SlabList {
+    const fn new() -> Self {
+        SlabList {
+            head: ptr::null_mut(),
+            len: 0,
+        }
+    }
+
+    unsafe fn push(&mut self, slab: *mut Slab) {
+        (*slab).prev = ptr::null_mut();
+        (*slab).next = self.head;
+        if !self.head.is_null() {
+            (*self.head).prev = slab;
+        }
+        self.head = slab;
+        self.len += 1;
+    }
+
+    unsafe fn remove(&mut self, slab: *mut Slab) {
+        if (*slab).prev.is_null() {
+            self.head = (*slab).next;
+        } else {
+            (*(*slab).prev).next = (*slab).next;
+        }
+        if !(*slab).next.is_null() {
+            (*(*slab).next).prev = (*slab).prev;
+        }
+        self.len -= 1;
+    }
+}
+
+/// SlabStats is a snapshot of what a slab cache is holding on to.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SlabStats {
+    /// object_size is how big each object in the cache is.
+    pub object_size: usize,
+    /// slabs is how many slabs the cache has.
+    pub slabs: usize,
+    /// empty_slabs is how many of those have nothing allocated out of them.
+    pub empty_slabs: usize,
+    /// objects is how many objects are allocated.
+    pub objects: usize,
+    /// reclaimed is how many empty slabs have been given back to the frame
+    /// allocator.
+    pub reclaimed: usize,
+}
+
+/// SlabCache is a cache of untyped objects of a single size.
+pub struct SlabCache {
+    name: &'static str,
+    /// size is the size of every object, including padding to keep the next
+    /// one aligned.
+    size: usize,
+    /// offset is where the first object in a slab starts.
+    offset: usize,
+    /// capacity is how many objects fit in a slab.
+    capacity: usize,
+    /// partial has the slabs with some objects allocated out of them.
+    partial: SlabList,
+    /// full has the slabs with no free objects left.
+    full: SlabList,
+    /// empty has the slabs with nothing allocated out of them.
+    empty: SlabList,
+    objects: usize,
+    reclaimed: usize,
+}
+
+// the cache only ever points into slabs it owns.
+unsafe impl Send for SlabCache {}
+
+impl SlabCache {
+    /// new makes a cache for objects of the given size and alignment, which
+    /// has to be a power of 2. it panics if the objects are bigger than
+    /// MAX_OBJECT_SIZE.
+    pub fn new(name: &'static str, size: usize, align: usize) -> Self {
+        assert!(align.is_power_of_two(), "'align' must be a power of 2");
+        assert!(size <= MAX_OBJECT_SIZE && align <= MAX_OBJECT_SIZE,
+                "{} objects are too big for a slab cache", name);
+
+        let align = cmp::max(align, mem::align_of::<FreeObject>());
+        let size = align_up(cmp::max(size, mem::size_of::<FreeObject>()), align);
+        SlabCache::with_layout(name, size, align)
+    }
+
+    /// with_layout makes a cache for objects of exactly the given size, which
+    /// has to be a multiple of the alignment.
+    pub const fn with_layout(name: &'static str, size: usize, align: usize) -> Self {
+        SlabCache {
+            name: name,
+            size: size,
+            offset: (mem::size_of::<Slab>() + align - 1) & !(align - 1),
+            capacity: (PAGE_SIZE - ((mem::size_of::<Slab>() + align - 1) & !(align - 1))) / size,
+            partial: SlabList::new(),
+            full: SlabList::new(),
+            empty: SlabList::new(),
+            objects: 0,
+            reclaimed: 0,
+        }
+    }
+
+    /// name returns the name the cache was made with.
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// alloc allocates an object, getting a new slab from the allocator if
+    /// every slab is full. it returns None if the allocator is out of frames.
+    pub fn alloc<A>(&mut self, allocator: &mut A) -> Option<NonNull<u8>>
+        where A: FrameAllocator
+    {
+        if let Some(object) = self.try_alloc() {
+            return Some(object);
+        }
+
+        unsafe {
+            let slab = self.new_slab(allocator)?;
+            self.partial.push(slab);
+            self.take(slab)
+        }
+    }
+
+    /// try_alloc allocates an object out of the slabs the cache already has.
+    /// it returns None if every slab is full, without going anywhere near a
+    /// frame allocator, so it's cheap to try first.
+    pub fn try_alloc(&mut self) -> Option<NonNull<u8>> {
+        unsafe {
+            let slab = if !self.partial.head.is_null() {
+                self.partial.head
+            } else if !self.empty.head.is_null() {
+                let slab = self.empty.head;
+                self.empty.remove(slab);
+                self.partial.push(slab);
+                slab
+            } else {
+                return None;
+            };
+            self.take(slab)
+        }
+    }
+
+    /// free gives back an object that was allocated from this cache. if that
+    /// leaves its slab empty, the slab sticks around until reclaim.
+    pub unsafe fn free(&mut self, object: NonNull<u8>) {
+        let object = object.as_ptr() as *mut FreeObject;
+        let slab = (object as usize & !(PAGE_SIZE - 1)) as *mut Slab;
+
+        if (*slab).in_use == self.capacity {
+            self.full.remove(slab);
+            self.partial.push(slab);
+        }
+
+        (*object).next = (*slab).free;
+        (*slab).free = object;
+        (*slab).in_use -= 1;
+        if (*slab).in_use == 0 {
+            self.partial.remove(slab);
+            self.empty.push(slab);
+        }
+
+        self.objects -= 1;
+    }
+
+    /// reclaim gives every empty slab past the first keep back to the
+    /// allocator. it returns how many it gave back.
+    pub fn reclaim<A>(&mut self, keep: usize, allocator: &mut A) -> usize
+        where A: FrameAllocator
+    {
+        let mut reclaimed = 0;
+        while self.empty.len > keep {
+            let slab = self.empty.head;
+            unsafe { self.empty.remove(slab) };
+            allocator.deallocate_frame(Frame::containing_address(slab as usize));
+            reclaimed += 1;
+        }
+        self.reclaimed += reclaimed;
+        reclaimed
+    }
+
+    /// empty_slabs returns how many slabs have nothing allocated out of them.
+    pub fn empty_slabs(&self) -> usize {
+        self.empty.len
+    }
+
+    /// stats returns a snapshot of the cache's statistics.
+    pub fn stats(&self) -> SlabStats {
+        SlabStats {
+            object_size: self.size,
+            slabs: self.partial.len + self.full.len + self.empty.len,
+            empty_slabs: self.empty.len,
+            objects: self.objects,
+            reclaimed: self.reclaimed,
+        }
+    }
+
+    /// take allocates the first free object in a slab on the partial list,
+    /// which has to have one.
+    unsafe fn take(&mut self, slab: *mut Slab) -> Option<NonNull<u8>> {
+        let object = (*slab).free;
+        (*slab).free = (*object).next;
+        (*slab).in_use += 1;
+        if (*slab).in_use == self.capacity {
+            self.partial.remove(slab);
+            self.full.push(slab);
+        }
+
+        self.objects += 1;
+        NonNull::new(object as *mut u8)
+    }
+
+    /// new_slab gets a frame from the allocator and cuts it up into objects,
+    /// all of which are on its free list.
+    unsafe fn new_slab<A>(&mut self, allocator: &mut A) -> Option<*mut Slab>
+        where A: FrameAllocator
+    {
+        let start = allocator.allocate_frame()?.start_address();
+
+        let mut free = ptr::null_mut();
+        for i in (0..self.capacity).rev() {
+            let object = (start + self.offset + i * self.size) as *mut FreeObject;
+            (*object).next = free;
+            free = object;
+        }
+
+        let slab = start as *mut Slab;
+        ptr::write(slab, Slab {
+            prev: ptr::null_mut(),
+            next: ptr::null_mut(),
+            free: free,
+            in_use: 0,
+        });
+        Some(slab)
+    }
+}
+
+/// ObjectCache is a slab cache for a particular type. every object it hands
+/// out has been set up by the constructor it was made with.
+pub struct ObjectCache<T> {
+    slabs: SlabCache,
+    constructor: fn() -> T,
+    _marker: PhantomData<T>,
+}
+
+impl<T> ObjectCache<T> {
+    /// new makes a cache for objects of type T, which run the constructor
+    /// every time one is allocated.
+    pub fn new(name: &'static str, constructor: fn() -> T) -> Self {
+        ObjectCache {
+            slabs: SlabCache::new(name, mem::size_of::<T>(), mem::align_of::<T>()),
+            constructor: constructor,
+            _marker: PhantomData,
+        }
+    }
+
+    /// alloc allocates an object and runs the constructor on it. it returns
+    /// None if the allocator is out of frames.
+    pub fn alloc<A>(&mut self, allocator: &mut A) -> Option<NonNull<T>>
+        where A: FrameAllocator
+    {
+        let object = self.slabs.alloc(allocator)?.cast::<T>();
+        unsafe { ptr::write(object.as_ptr(), (self.constructor)()) };
+        Some(object)
+    }
+
+    /// free drops an object that was allocated from this cache and gives it
+    /// back.
+    pub unsafe fn free(&mut self, object: NonNull<T>) {
+        ptr::drop_in_place(object.as_ptr());
+        self.slabs.free(object.cast());
+    }
+
+    /// reclaim gives every empty slab past the first keep back to the
+    /// allocator. it returns how many it gave back.
+    pub fn reclaim<A>(&mut self, keep: usize, allocator: &mut A) -> usize
+        where A: FrameAllocator
+    {
+        self.slabs.reclaim(keep, allocator)
+    }
+
+    /// stats returns a snapshot of the cache's statistics.
+    pub fn stats(&self) -> SlabStats {
+        self.slabs.stats()
+    }
+}
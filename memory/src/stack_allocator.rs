@@ -42,15 +42,18 @@ impl StackAllocator {
 
         match (guard_page, stack_start, stack_end) {
             (Some(_), Some(start), Some(end)) => {
+                // map stack pages to physical frames. nothing should ever
+                // run code off of a stack.
+                let flags = paging::EntryFlags::WRITABLE | paging::EntryFlags::NO_EXECUTE;
+                if active_table.map_range(Page::range_inclusive(start, end), flags,
+                                          frame_allocator).is_err() {
+                    // not enough frames
+                    return None;
+                }
+
                 // success! write back updated range
                 self.range = range;
 
-                // map stack pages to physical frames
-                for page in Page::range_inclusive(start, end) {
-                    active_table.map(page, paging::EntryFlags::WRITABLE,
-                                     frame_allocator);
-                }
-
                 // create a new stack
                 let top_of_stack = end.start_address() + PAGE_SIZE;
                 Some(Stack::new(top_of_stack, start.start_address()))
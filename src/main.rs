@@ -5,25 +5,111 @@
 #![no_std]
 #![no_main]
 
+extern crate bootinfo;
+extern crate memory;
 extern crate spin;
 extern crate x86_64;
 
+mod modules;
+mod runtime;
 mod serial;
 
+use bootinfo::{BootInfo, BootTimes};
+use core::arch::x86_64::_rdtsc;
+use core::fmt::Write;
+use core::mem;
 use core::panic::PanicInfo;
+use memory::heap_allocator::LockedHeap;
 
+/// HEAP is the kernel heap. memory::init maps it and hands it its memory.
+#[global_allocator]
+static HEAP: LockedHeap = LockedHeap::empty();
+
+/// kernel_main is the entrypoint of the kernel. the bootloader calls it with a
+/// pointer to the boot information as the first argument, after it has mapped
+/// us into the higher half and exited boot services.
 #[no_mangle]
-pub extern "C" fn kernel_main() -> ! {
+pub extern "C" fn kernel_main(boot_info: &'static BootInfo) -> ! {
+    let entered = unsafe { _rdtsc() };
+
     unsafe {
         // initialize serial output
         serial::init();
     }
 
-    let hello = "hello world";
     let mut console = serial::COM1.lock();
 
-    for byte in hello.bytes() {
-        console.send(byte);
+    writeln!(console, "hello world").unwrap();
+
+    // if the bootloader and the kernel disagree on what the boot information
+    // looks like, there is nothing useful we can do with it.
+    if !boot_info.is_valid() {
+        writeln!(console, "invalid boot info: magic {:#x}, version {}",
+                 boot_info.magic, boot_info.version).unwrap();
+        loop {}
+    }
+
+    writeln!(console, "kernel: phys {:#x}-{:#x}, virt {:#x}-{:#x}, slide {:#x}",
+             boot_info.kernel.phys_start, boot_info.kernel.phys_end,
+             boot_info.kernel.virt_start, boot_info.kernel.virt_end,
+             boot_info.kernel.slide).unwrap();
+    writeln!(console, "cmdline: {}", boot_info.cmdline.as_str()).unwrap();
+    if let Some(fb) = boot_info.framebuffer() {
+        writeln!(console, "framebuffer: {}x{} {:?} at {:#x}",
+                 fb.width, fb.height, fb.format, fb.base).unwrap();
+    }
+    if let Some(rsdp) = boot_info.acpi_rsdp() {
+        writeln!(console, "acpi rsdp: {:#x}", rsdp).unwrap();
+    }
+    if let Some(smbios) = boot_info.smbios() {
+        writeln!(console, "smbios: {:#x}", smbios).unwrap();
+    }
+    writeln!(console, "memory map: {} regions",
+             boot_info.memory_map.regions().len()).unwrap();
+
+    print_boot_times(&mut *console, boot_info, entered);
+
+    // take over memory management from the bootloader
+    let memory = memory::init(memory::handoff::memory_regions(boot_info),
+                              &memory::handoff::kernel_image(boot_info),
+                              &HEAP);
+    {
+        let memory = memory.lock();
+        writeln!(console, "memory: {} frames free, {} used",
+                 memory.free_frames(), memory.used_frames()).unwrap();
+    }
+    let heap = HEAP.stats();
+    writeln!(console, "heap: {} bytes, {} free", heap.size, heap.free).unwrap();
+
+    modules::init(boot_info);
+    for module in modules::all() {
+        writeln!(console, "module: {} ({} bytes at {:#x})",
+                 module.name(), module.size, module.phys_start).unwrap();
+    }
+
+    runtime::init(boot_info);
+    match runtime::time() {
+        Ok(time) => writeln!(console, "time: {}", time).unwrap(),
+        Err(status) => writeln!(console, "failed to read the time: {}", status).unwrap(),
+    }
+
+    // count how many times we've booted, which is a handy way to check that
+    // firmware variables actually stick around.
+    let mut count = [0u8; 4];
+    let boots = match runtime::variable("BootCount", &runtime::DEMOS_VARIABLE, &mut count) {
+        Ok((4, _)) => unsafe { mem::transmute::<[u8; 4], u32>(count) } + 1,
+        Ok(_) | Err(runtime::Status::NOT_FOUND) => 1,
+        Err(status) => {
+            writeln!(console, "failed to read the boot count: {}", status).unwrap();
+            1
+        },
+    };
+    let count = unsafe { mem::transmute::<u32, [u8; 4]>(boots) };
+    let attributes = runtime::VARIABLE_NON_VOLATILE | runtime::VARIABLE_BOOTSERVICE_ACCESS |
+        runtime::VARIABLE_RUNTIME_ACCESS;
+    match runtime::set_variable("BootCount", &runtime::DEMOS_VARIABLE, attributes, &count) {
+        Ok(()) => writeln!(console, "boot count: {}", boots).unwrap(),
+        Err(status) => writeln!(console, "failed to write the boot count: {}", status).unwrap(),
     }
 
     // info!("Hello World!");
@@ -31,6 +117,30 @@ pub extern "C" fn kernel_main() -> ! {
     loop {}
 }
 
+/// print_boot_times prints how long each phase of the boot took, and how long it
+/// took altogether, up to when we entered the kernel.
+fn print_boot_times<W: Write>(w: &mut W, boot_info: &BootInfo, entered: u64) {
+    let times = &boot_info.times;
+
+    writeln!(w, "boot times:").unwrap();
+    for &(name, ticks) in times.phases().iter() {
+        print_boot_time(w, times, name, ticks);
+    }
+    print_boot_time(w, times, "enter kernel", entered.saturating_sub(times.remap));
+    print_boot_time(w, times, "total", entered.saturating_sub(times.start));
+}
+
+/// print_boot_time prints how long a single phase of the boot took. without
+/// the tsc frequency, all we have is ticks.
+fn print_boot_time<W: Write>(w: &mut W, times: &BootTimes, name: &str, ticks: u64) {
+    match times.micros(ticks) {
+        Some(micros) =>
+            writeln!(w, "  {:<20} {}.{:03}ms", name, micros / 1000, micros % 1000),
+        None =>
+            writeln!(w, "  {:<20} {} ticks", name, ticks),
+    }.unwrap();
+}
+
 /// panic_impl is a language-level function that rust expects to be provided. it
 /// is the function called when something `panic!`s. it is given the file the
 /// panic occured in, the line it occured on, and a message about what happened.
@@ -0,0 +1,20 @@
+//! modules keeps track of the boot modules the bootloader loaded for us. they
+//! are read-only as far as the kernel is concerned, and they live in memory the
+//! bootloader owns, so we just hold on to the list it gave us.
+
+use bootinfo::{BootInfo, Module};
+use spin::Once;
+
+static MODULES: Once<&'static [Module]> = Once::new();
+
+/// init records the module list from the boot info. it only does anything the
+/// first time it's called.
+pub fn init(boot_info: &'static BootInfo) {
+    MODULES.call_once(|| boot_info.modules.modules());
+}
+
+/// all returns every module the bootloader loaded, or nothing if init hasn't
+/// been called yet.
+pub fn all() -> &'static [Module] {
+    MODULES.try().map(|modules| *modules).unwrap_or(&[])
+}
@@ -0,0 +1,209 @@
+//! runtime wraps the uefi runtime services, which are the bits of the firmware
+//! that stick around after the bootloader exits boot services. they give us
+//! the real time clock, firmware variables, and a way to reset the machine.
+//!
+//! the bootloader has already called SetVirtualAddressMap and mapped the
+//! firmware's runtime regions where it said it would, so the table it hands us
+//! is ready to use as long as that mapping is. the firmware isn't reentrant,
+//! so every call goes through a lock.
+
+use bootinfo::BootInfo;
+use core::{fmt, ptr};
+use spin::{Mutex, Once};
+
+static RUNTIME: Once<Mutex<&'static RuntimeServices>> = Once::new();
+
+/// VARIABLE_NAME_MAX is the longest variable name we can pass to the firmware,
+/// in utf-16 code units, not counting the terminating null.
+const VARIABLE_NAME_MAX: usize = 127;
+
+/// init records the runtime services table from the boot info. if the
+/// bootloader didn't give us one, every call fails with UNSUPPORTED.
+pub fn init(boot_info: &'static BootInfo) {
+    if let Some(addr) = boot_info.runtime_services() {
+        RUNTIME.call_once(|| Mutex::new(unsafe { &*(addr as *const RuntimeServices) }));
+    }
+}
+
+/// Status is a uefi status code. anything other than SUCCESS is an error,
+/// which is the only kind we ever hand back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Status(pub usize);
+
+/// ERROR_BIT is set in every uefi status code that is an error.
+const ERROR_BIT: usize = 1 << 63;
+
+impl Status {
+    pub const SUCCESS: Status = Status(0);
+    pub const INVALID_PARAMETER: Status = Status(ERROR_BIT | 2);
+    pub const UNSUPPORTED: Status = Status(ERROR_BIT | 3);
+    pub const BUFFER_TOO_SMALL: Status = Status(ERROR_BIT | 5);
+    pub const NOT_FOUND: Status = Status(ERROR_BIT | 14);
+
+    /// into_result turns a status the firmware handed back into a result.
+    fn into_result(self) -> Result<(), Status> {
+        if self == Status::SUCCESS {
+            Ok(())
+        } else {
+            Err(self)
+        }
+    }
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Status::SUCCESS => write!(f, "success"),
+            Status::INVALID_PARAMETER => write!(f, "invalid parameter"),
+            Status::UNSUPPORTED => write!(f, "unsupported"),
+            Status::BUFFER_TOO_SMALL => write!(f, "buffer too small"),
+            Status::NOT_FOUND => write!(f, "not found"),
+            Status(status) => write!(f, "status {:#x}", status),
+        }
+    }
+}
+
+/// Guid identifies the vendor a firmware variable belongs to.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Guid {
+    pub data1: u32,
+    pub data2: u16,
+    pub data3: u16,
+    pub data4: [u8; 8],
+}
+
+/// DEMOS_VARIABLE is the vendor of the variables the kernel keeps for itself.
+pub const DEMOS_VARIABLE: Guid = Guid {
+    data1: 0x5d6f7a2c,
+    data2: 0x3e1b,
+    data3: 0x4c8a,
+    data4: [0x9f, 0x1d, 0x6b, 0x2e, 0x8a, 0x4c, 0x7d, 0x31],
+};
+
+/// the attributes a firmware variable can have.
+pub const VARIABLE_NON_VOLATILE: u32 = 0x1;
+pub const VARIABLE_BOOTSERVICE_ACCESS: u32 = 0x2;
+pub const VARIABLE_RUNTIME_ACCESS: u32 = 0x4;
+
+/// Time is the time according to the real time clock.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Time {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+    _pad1: u8,
+    pub nanosecond: u32,
+    /// time_zone is the offset from utc in minutes, or 2047 if the clock is
+    /// in local time.
+    pub time_zone: i16,
+    pub daylight: u8,
+    _pad2: u8,
+}
+
+impl fmt::Display for Time {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+               self.year, self.month, self.day, self.hour, self.minute, self.second)
+    }
+}
+
+/// ResetType is the kind of reset to ask the firmware for.
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResetType {
+    /// Cold resets every piece of hardware, like pulling the plug.
+    Cold = 0,
+    /// Warm resets the processors, and leaves the rest up to the platform.
+    Warm = 1,
+    /// Shutdown turns the machine off.
+    Shutdown = 2,
+}
+
+/// RuntimeServices is the EFI_RUNTIME_SERVICES table. the functions we don't
+/// wrap are just placeholders, to keep the layout right.
+#[repr(C)]
+struct RuntimeServices {
+    header: [u8; 24],
+    get_time: extern "win64" fn(*mut Time, *mut u8) -> Status,
+    set_time: usize,
+    get_wakeup_time: usize,
+    set_wakeup_time: usize,
+    set_virtual_address_map: usize,
+    convert_pointer: usize,
+    get_variable: extern "win64" fn(*const u16, *const Guid, *mut u32, *mut usize, *mut u8)
+                                    -> Status,
+    get_next_variable_name: usize,
+    set_variable: extern "win64" fn(*const u16, *const Guid, u32, usize, *const u8) -> Status,
+    get_next_high_monotonic_count: usize,
+    reset_system: extern "win64" fn(ResetType, Status, usize, *const u8) -> !,
+}
+
+/// with_runtime calls f with the runtime services table, while holding the
+/// lock, or fails with UNSUPPORTED if there isn't one.
+fn with_runtime<F, T>(f: F) -> Result<T, Status>
+    where F: FnOnce(&RuntimeServices) -> Result<T, Status>
+{
+    let runtime = RUNTIME.try().ok_or(Status::UNSUPPORTED)?;
+    let runtime = runtime.lock();
+    f(*runtime)
+}
+
+/// variable_name converts a variable name into the null-terminated utf-16 the
+/// firmware wants.
+fn variable_name(name: &str) -> Result<[u16; VARIABLE_NAME_MAX + 1], Status> {
+    let mut buf = [0; VARIABLE_NAME_MAX + 1];
+    for (i, c) in name.encode_utf16().enumerate() {
+        if i == VARIABLE_NAME_MAX {
+            return Err(Status::INVALID_PARAMETER);
+        }
+        buf[i] = c;
+    }
+    Ok(buf)
+}
+
+/// time reads the real time clock.
+pub fn time() -> Result<Time, Status> {
+    with_runtime(|runtime| {
+        let mut time = Time::default();
+        (runtime.get_time)(&mut time, ptr::null_mut()).into_result()?;
+        Ok(time)
+    })
+}
+
+/// variable reads the firmware variable with the given name and vendor into
+/// buf. it returns how many bytes the variable has, and its attributes. if buf
+/// is too small, it fails with BUFFER_TOO_SMALL.
+pub fn variable(name: &str, vendor: &Guid, buf: &mut [u8]) -> Result<(usize, u32), Status> {
+    let name = variable_name(name)?;
+    with_runtime(|runtime| {
+        let mut attributes = 0;
+        let mut size = buf.len();
+        (runtime.get_variable)(name.as_ptr(), vendor, &mut attributes, &mut size,
+                               buf.as_mut_ptr()).into_result()?;
+        Ok((size, attributes))
+    })
+}
+
+/// set_variable writes the firmware variable with the given name and vendor.
+/// writing an empty variable deletes it.
+pub fn set_variable(name: &str, vendor: &Guid, attributes: u32, data: &[u8]) -> Result<(), Status> {
+    let name = variable_name(name)?;
+    with_runtime(|runtime| {
+        (runtime.set_variable)(name.as_ptr(), vendor, attributes, data.len(), data.as_ptr())
+            .into_result()
+    })
+}
+
+/// reset asks the firmware to reset the machine. it only returns if there are
+/// no runtime services to ask.
+pub fn reset(kind: ResetType) -> Status {
+    match RUNTIME.try() {
+        Some(runtime) => (runtime.lock().reset_system)(kind, Status::SUCCESS, 0, ptr::null()),
+        None => Status::UNSUPPORTED,
+    }
+}
@@ -1,5 +1,6 @@
 //! serial driver
 
+use core::fmt;
 use spin::Mutex;
 use x86_64::instructions::port::Port;
 
@@ -99,3 +100,12 @@ impl SerialPort {
         }
     }
 }
+
+impl fmt::Write for SerialPort {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            self.send(byte);
+        }
+        Ok(())
+    }
+}